@@ -0,0 +1,320 @@
+//! Procedural macros for `i-cant-believe-its-not-bsn`. These are re-exported by
+//! that crate, which is where they are documented.
+
+use proc_macro2::{Group, Span, TokenStream, TokenTree};
+use proc_macro_crate::{crate_name, FoundCrate};
+use quote::{quote, ToTokens};
+use syn::{parse_macro_input, Ident};
+
+mod parse;
+
+use parse::{
+    Arm, Body, Conditional, Fragment, Item, List, Match, Memo, Name, Observer, Related, Template,
+    Widget,
+};
+
+/// Builds a `Template` from a list of fragments. See the documentation of the
+/// re-export in `i-cant-believe-its-not-bsn` for the full syntax.
+#[proc_macro]
+pub fn template(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let template = parse_macro_input!(input as Template);
+    expand_template(&template).into()
+}
+
+/// The path of the runtime crate, under the name it was given in the calling
+/// crate's manifest, so that renamed dependencies work. The crate declares
+/// itself under its own name as well, so that the macro can be used inside it,
+/// and that name is used if it isn't a direct dependency at all.
+fn krate() -> TokenStream {
+    match crate_name("i-cant-believe-its-not-bsn") {
+        Ok(FoundCrate::Name(name)) => {
+            let name = Ident::new(&name, Span::call_site());
+            quote!(::#name)
+        }
+        Ok(FoundCrate::Itself) | Err(_) => quote!(::i_cant_believe_its_not_bsn),
+    }
+}
+
+/// The name of the list of prototypes being built. It has mixed-site hygiene so
+/// that it can't collide with names used inside the template's blocks.
+fn fragments() -> Ident {
+    Ident::new("fragments", Span::mixed_site())
+}
+
+/// Expands into an expression returning a `Key` of a `Site`, which identifies
+/// the place in the source where it is expanded. A unit struct is declared
+/// there, and its `TypeId` is unique to that place. When the same template is
+/// spliced more than once among the same siblings, its scopes are numbered by
+/// occurrence while the siblings are matched.
+fn site_key(kind: &str, index: usize) -> TokenStream {
+    let krate = krate();
+    let here = Ident::new("Here", Span::mixed_site());
+    quote! {{
+        struct #here;
+        #krate::Key::new(#krate::Site {
+            id: ::core::any::TypeId::of::<#here>(),
+            kind: #kind,
+            index: #index,
+        })
+    }}
+}
+
+/// Expands a template into an expression returning a `Template`.
+fn expand_template(template: &Template) -> TokenStream {
+    let krate = krate();
+    let fragments = fragments();
+    let items = template.items.iter().map(expand_item);
+    quote! {{
+        #[allow(unused_mut)]
+        let mut #fragments: #krate::Template = ::std::vec::Vec::new();
+        #( #items )*
+        #fragments
+    }}
+}
+
+/// Expands an item into statements which push its prototypes to the list.
+fn expand_item(item: &Item) -> TokenStream {
+    let fragments = fragments();
+    match item {
+        Item::Splice(block) => quote! {
+            #[allow(unused_braces)]
+            #fragments.extend(#block);
+        },
+        Item::List(list) => expand_list(list),
+        Item::Conditional(conditional) => expand_conditional(conditional),
+        Item::Match(match_) => expand_match(match_),
+        Item::Widget(Widget { name, block }) => {
+            let krate = krate();
+            let anchor = expand_anchor(name);
+            quote! {
+                #[allow(unused_braces)]
+                #fragments.push(::std::boxed::Box::new(#krate::WidgetPrototype {
+                    anchor: #anchor,
+                    widget: #krate::__private::widget(#block),
+                }));
+            }
+        }
+        Item::Memo(Memo {
+            name,
+            input,
+            template,
+        }) => {
+            let krate = krate();
+            let anchor = expand_anchor(name);
+            let template = expand_template(template);
+            quote! {
+                #fragments.push(::std::boxed::Box::new(#krate::Memo {
+                    anchor: #anchor,
+                    ..#krate::Memo::new(#input, #template)
+                }));
+            }
+        }
+        Item::Fragment(Fragment { name, body }) => expand_fragment(&expand_anchor(name), body),
+    }
+}
+
+/// Expands an optional name into an expression returning an `Option<Anchor>`.
+fn expand_anchor(name: &Option<Name>) -> TokenStream {
+    let krate = krate();
+    match name {
+        None => quote!(::core::option::Option::None),
+        Some(Name::Static(name)) => {
+            let name = name.to_string();
+            quote!(::core::option::Option::Some(#krate::Anchor::Named(
+                ::std::string::String::from(#name)
+            )))
+        }
+        Some(Name::Dynamic(block)) => {
+            quote!(::core::option::Option::Some(#krate::Anchor::Dynamic(
+                ::std::string::ToString::to_string(&#block)
+            )))
+        }
+    }
+}
+
+/// Expands a fragment with the given anchor expression into a push statement.
+fn expand_fragment(anchor: &TokenStream, body: &Body) -> TokenStream {
+    let krate = krate();
+    let fragments = fragments();
+    let Body {
+        block,
+        observers,
+        context,
+        children,
+        related,
+    } = body;
+    let children = expand_template(children);
+    let observers = observers.iter().map(|Observer { system, key }| {
+        let key = key.as_ref().map(|key| quote!(.with_key(#key)));
+        quote!(#krate::FragmentObserver::new(#system) #key)
+    });
+    let related = related.iter().map(
+        |Related {
+             relationship,
+             template,
+         }| {
+            let template = expand_template(template);
+            quote!(.related::<#relationship>(#template))
+        },
+    );
+
+    // The bundle is passed through a function with the `Bundle` bound, so that
+    // a block returning something else is reported at the block itself.
+    let references = Ident::new("references", Span::mixed_site());
+    let mut names = Vec::new();
+    let block = replace_references(block.to_token_stream(), &references, &mut names);
+    let fragment = |bundle: TokenStream, resolve: TokenStream| {
+        quote! {
+            #krate::Fragment {
+                anchor: #anchor,
+                bundle: #bundle,
+                children: #children,
+                observers: ::std::vec![#( #observers ),*],
+                context: ::std::vec![#( #krate::ContextProvider::new(#context) ),*],
+                related: ::std::vec::Vec::new(),
+                resolve: #resolve,
+            } #( #related )*
+        }
+    };
+    let fragment = if names.is_empty() {
+        fragment(
+            quote!(#krate::__private::bundle(#block)),
+            quote!(::core::option::Option::None),
+        )
+    } else {
+        // A block with references is run once with placeholders, and again
+        // once the template has been built and the references are known.
+        let resolve = Ident::new("resolve", Span::mixed_site());
+        let fragment = fragment(
+            quote!(#resolve.resolve(&#krate::References::default())),
+            quote!(::core::option::Option::Some(#resolve)),
+        );
+        quote! {{
+            let #resolve = #krate::ResolveBundle::new(
+                [#( #names ),*],
+                move |#references: &#krate::References| #krate::__private::bundle(#block),
+            );
+            #fragment
+        }}
+    };
+    quote! {
+        #[allow(unused_braces)]
+        #fragments.push(::std::boxed::Box::new(#fragment));
+    }
+}
+
+/// Replaces every `#name` reference in a block with a lookup in `references`,
+/// and collects the names which were referred to.
+fn replace_references(
+    tokens: TokenStream,
+    references: &Ident,
+    names: &mut Vec<String>,
+) -> TokenStream {
+    let mut output = TokenStream::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Punct(punct)
+                if punct.as_char() == '#' && matches!(tokens.peek(), Some(TokenTree::Ident(_))) =>
+            {
+                let Some(TokenTree::Ident(name)) = tokens.next() else {
+                    unreachable!();
+                };
+                let name = name.to_string();
+                if !names.contains(&name) {
+                    names.push(name.clone());
+                }
+                output.extend(quote!(#references.get(#name)));
+            }
+            TokenTree::Group(group) => {
+                let stream = replace_references(group.stream(), references, names);
+                let mut replaced = Group::new(group.delimiter(), stream);
+                replaced.set_span(group.span());
+                output.extend([TokenTree::Group(replaced)]);
+            }
+            token => output.extend([token]),
+        }
+    }
+    output
+}
+
+fn expand_list(list: &List) -> TokenStream {
+    let krate = krate();
+    let List {
+        pat,
+        iter,
+        key,
+        body,
+    } = list;
+    // The keys are scoped under the list, so that they can't collide with the
+    // keys of another list among the same siblings.
+    let scope = Ident::new("scope", Span::mixed_site());
+    let site = site_key("@for", 0);
+    let anchor = quote! {
+        ::core::option::Option::Some(#krate::Anchor::Scoped(
+            ::core::clone::Clone::clone(&#scope),
+            0,
+            ::std::boxed::Box::new(#krate::Anchor::Keyed(#krate::Key::new(#key))),
+        ))
+    };
+    let fragment = expand_fragment(&anchor, body);
+    quote! {
+        let #scope = #site;
+        for #pat in #iter {
+            #fragment
+        }
+    }
+}
+
+fn expand_conditional(conditional: &Conditional) -> TokenStream {
+    let krate = krate();
+    let fragments = fragments();
+    let Conditional {
+        cond,
+        then,
+        otherwise,
+    } = conditional;
+    // Each branch is scoped under the conditional and its index, so that it has
+    // its own identity among its siblings.
+    let branch = |template: &Template, index: usize| {
+        let template = expand_template(template);
+        let scope = site_key("@if", index);
+        quote! {
+            #fragments.extend(#krate::scoped(#scope, #template));
+        }
+    };
+    let then = branch(then, 0);
+    let otherwise = otherwise.as_ref().map(|otherwise| {
+        let otherwise = branch(otherwise, 1);
+        quote!(else { #otherwise })
+    });
+    quote! {
+        if #cond { #then } #otherwise
+    }
+}
+
+fn expand_match(match_: &Match) -> TokenStream {
+    let krate = krate();
+    let fragments = fragments();
+    let Match { scrutinee, arms } = match_;
+    // Each arm is scoped under the match and its index, so that it has its own
+    // identity among its siblings.
+    let arms = arms
+        .iter()
+        .enumerate()
+        .map(|(index, Arm { pat, guard, body })| {
+            let guard = guard.as_ref().map(|guard| quote!(if #guard));
+            let body = expand_template(body);
+            let scope = site_key("@match", index);
+            quote! {
+                #pat #guard => {
+                    #fragments.extend(#krate::scoped(#scope, #body));
+                }
+            }
+        });
+    quote! {
+        match #scrutinee {
+            #( #arms )*
+        }
+    }
+}
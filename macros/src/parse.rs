@@ -0,0 +1,386 @@
+use proc_macro2::{TokenStream, TokenTree};
+use syn::{
+    braced, bracketed,
+    buffer::Cursor,
+    parenthesized,
+    parse::{Parse, ParseStream},
+    token, Block, Expr, Ident, Pat, Path, Result, Token,
+};
+
+/// `<template> = *( <item> )`
+pub struct Template {
+    pub items: Vec<Item>,
+}
+
+/// `<item> = ( <splice> | <list> | <conditional> | <match> | <widget> | <memo> | <fragment> ) ";"`
+pub enum Item {
+    Splice(Block),
+    List(Box<List>),
+    Conditional(Conditional),
+    Match(Match),
+    Widget(Widget),
+    Memo(Memo),
+    Fragment(Fragment),
+}
+
+/// `<list> = "@for" <$pat> "in" <$expr> "=>" "key(" <$expr> ")" <body>`
+pub struct List {
+    pub pat: Pat,
+    pub iter: Expr,
+    pub key: Expr,
+    pub body: Body,
+}
+
+/// `<conditional> = "@if" <$cond> "{" <template> "}" ( "@else" "{" <template> "}" )?`
+pub struct Conditional {
+    pub cond: Expr,
+    pub then: Template,
+    pub otherwise: Option<Template>,
+}
+
+/// `<match> = "@match" <$expr> "{" <arm> *( "," <arm> ) ","? "}"`
+pub struct Match {
+    pub scrutinee: Expr,
+    pub arms: Vec<Arm>,
+}
+
+/// `<arm> = <$pat> ( "if" <$expr> )? "=>" "{" <template> "}"`
+pub struct Arm {
+    pub pat: Pat,
+    pub guard: Option<Expr>,
+    pub body: Template,
+}
+
+/// `<widget> = <name>? "@widget" <$block>`
+pub struct Widget {
+    pub name: Option<Name>,
+    pub block: Block,
+}
+
+/// `<memo> = <name>? "@memo" "(" <$expr> ")" "{" <template> "}"`
+pub struct Memo {
+    pub name: Option<Name>,
+    pub input: Expr,
+    pub template: Template,
+}
+
+/// `<fragment> = <name>? <body>`
+pub struct Fragment {
+    pub name: Option<Name>,
+    pub body: Body,
+}
+
+/// `<body> = <$block> *( <observer> | <provide> ) <children>? *( <related> )`
+///
+/// The block is kept as raw tokens, because it may contain `#name` references
+/// which aren't valid rust until they are replaced.
+pub struct Body {
+    pub block: TokenTree,
+    pub observers: Vec<Observer>,
+    pub context: Vec<Expr>,
+    pub children: Template,
+    pub related: Vec<Related>,
+}
+
+/// `<observer> = "on" "(" <$expr> ( "=>" "key(" <$expr> ")" )? ")"`
+pub struct Observer {
+    pub system: Expr,
+    pub key: Option<Expr>,
+}
+
+/// `<related> = "[" <$path> "=>" <template> "]"`
+pub struct Related {
+    pub relationship: Path,
+    pub template: Template,
+}
+
+/// `<name> = ( <$ident> | <$block> ) ":"`
+pub enum Name {
+    Static(Ident),
+    Dynamic(Block),
+}
+
+impl Parse for Template {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut items = Vec::new();
+        while !input.is_empty() {
+            items.push(input.parse()?);
+        }
+        Ok(Template { items })
+    }
+}
+
+impl Parse for Item {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let begin = input.cursor();
+        let name = input.call(parse_name)?;
+        let item = if input.peek(Token![@]) {
+            input.parse::<Token![@]>()?;
+            if is_keyword(input, "widget") {
+                input.parse::<Ident>()?;
+                let block = input.parse()?;
+                Item::Widget(Widget { name, block })
+            } else if is_keyword(input, "memo") {
+                input.parse::<Ident>()?;
+                let input_tokens;
+                parenthesized!(input_tokens in input);
+                let memo_input = input_tokens.parse()?;
+                let template;
+                braced!(template in input);
+                Item::Memo(Memo {
+                    name,
+                    input: memo_input,
+                    template: template.parse()?,
+                })
+            } else if name.is_some() {
+                return Err(input.error(
+                    "expected `widget` or `memo` after `@`, only fragments, widgets and memos can be named",
+                ));
+            } else if input.peek(Token![for]) {
+                Item::List(input.parse()?)
+            } else if input.peek(Token![if]) {
+                Item::Conditional(input.parse()?)
+            } else if input.peek(Token![match]) {
+                Item::Match(input.parse()?)
+            } else if input.peek(token::Brace) {
+                Item::Splice(input.parse()?)
+            } else {
+                return Err(input.error(
+                    "expected `for`, `if`, `match`, `widget`, `memo` or a block after `@`",
+                ));
+            }
+        } else {
+            let body = input.parse()?;
+            Item::Fragment(Fragment { name, body })
+        };
+
+        // Point a missing semicolon at the whole item, rather than at whatever
+        // token happens to follow it.
+        if !input.peek(Token![;]) {
+            let tokens = tokens_between(begin, input.cursor());
+            let message = format!("expected `;` after {}", item.description());
+            return Err(syn::Error::new_spanned(tokens, message));
+        }
+        input.parse::<Token![;]>()?;
+        Ok(item)
+    }
+}
+
+impl Item {
+    /// A description of the item, for use in error messages.
+    fn description(&self) -> &'static str {
+        match self {
+            Item::Splice(_) => "splice",
+            Item::List(_) => "`@for` list",
+            Item::Conditional(_) => "`@if` conditional",
+            Item::Match(_) => "`@match`",
+            Item::Widget(_) => "widget",
+            Item::Memo(_) => "`@memo`",
+            Item::Fragment(_) => "fragment",
+        }
+    }
+}
+
+impl Parse for List {
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.parse::<Token![for]>()?;
+        let pat = Pat::parse_multi_with_leading_vert(input)?;
+        input.parse::<Token![in]>()?;
+        let iter = input.parse()?;
+        input.parse::<Token![=>]>()?;
+        let key = parse_key(input)?;
+        let body = input.parse()?;
+        Ok(List {
+            pat,
+            iter,
+            key,
+            body,
+        })
+    }
+}
+
+impl Parse for Conditional {
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.parse::<Token![if]>()?;
+        let cond = Expr::parse_without_eager_brace(input)?;
+        let then;
+        braced!(then in input);
+        let then = then.parse()?;
+        let otherwise = if input.peek(Token![@]) && input.peek2(Token![else]) {
+            input.parse::<Token![@]>()?;
+            input.parse::<Token![else]>()?;
+            let otherwise;
+            braced!(otherwise in input);
+            Some(otherwise.parse()?)
+        } else {
+            None
+        };
+        Ok(Conditional {
+            cond,
+            then,
+            otherwise,
+        })
+    }
+}
+
+impl Parse for Match {
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.parse::<Token![match]>()?;
+        let scrutinee = Expr::parse_without_eager_brace(input)?;
+        let content;
+        braced!(content in input);
+        let mut arms = Vec::new();
+        while !content.is_empty() {
+            arms.push(content.parse()?);
+            if content.is_empty() {
+                break;
+            }
+            content.parse::<Token![,]>()?;
+        }
+        Ok(Match { scrutinee, arms })
+    }
+}
+
+impl Parse for Arm {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let pat = Pat::parse_multi_with_leading_vert(input)?;
+        let guard = if input.peek(Token![if]) {
+            input.parse::<Token![if]>()?;
+            Some(input.parse()?)
+        } else {
+            None
+        };
+        input.parse::<Token![=>]>()?;
+        let body;
+        braced!(body in input);
+        let body = body.parse()?;
+        Ok(Arm { pat, guard, body })
+    }
+}
+
+/// Parses an optional name, followed by a colon.
+fn parse_name(input: ParseStream) -> Result<Option<Name>> {
+    let name = if input.peek(Ident) && input.peek2(Token![:]) && !input.peek2(Token![::]) {
+        Name::Static(input.parse()?)
+    } else if input.peek(token::Brace) && is_dynamic_name(input) {
+        Name::Dynamic(input.parse()?)
+    } else {
+        return Ok(None);
+    };
+    input.parse::<Token![:]>()?;
+    Ok(Some(name))
+}
+
+/// Parses `"key(" <$expr> ")"`.
+fn parse_key(input: ParseStream) -> Result<Expr> {
+    let key_ident = input.parse::<Ident>()?;
+    if key_ident != "key" {
+        return Err(syn::Error::new(key_ident.span(), "expected `key(...)`"));
+    }
+    let key;
+    parenthesized!(key in input);
+    key.parse()
+}
+
+/// Returns true if the input starts with the given contextual keyword.
+fn is_keyword(input: ParseStream, keyword: &str) -> bool {
+    input
+        .fork()
+        .parse::<Ident>()
+        .is_ok_and(|ident| ident == keyword)
+}
+
+/// Returns true if the input starts with a block followed by a single colon.
+fn is_dynamic_name(input: ParseStream) -> bool {
+    let fork = input.fork();
+    fork.parse::<Block>().is_ok() && fork.peek(Token![:]) && !fork.peek(Token![::])
+}
+
+impl Parse for Body {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if !input.peek(token::Brace) {
+            return Err(input.error("expected a block returning a bundle"));
+        }
+        let block = input.parse::<TokenTree>()?;
+
+        // `<observer> = "on" "(" <$expr> ( "=>" "key(" <$expr> ")" )? ")"`
+        // `<provide> = "provide" "(" <$expr> ")"`
+        let mut observers = Vec::new();
+        let mut context = Vec::new();
+        while input.peek(Ident) {
+            let keyword = input.parse::<Ident>()?;
+            let content;
+            parenthesized!(content in input);
+            if keyword == "on" {
+                let system = content.parse()?;
+                let key = if content.peek(Token![=>]) {
+                    content.parse::<Token![=>]>()?;
+                    Some(parse_key(&content)?)
+                } else {
+                    None
+                };
+                observers.push(Observer { system, key });
+            } else if keyword == "provide" {
+                context.push(content.parse()?);
+            } else {
+                return Err(syn::Error::new(
+                    keyword.span(),
+                    "expected `on(...)`, `provide(...)`, `[` or `;`",
+                ));
+            }
+        }
+
+        // `<children> = "[" <template> "]"`
+        let mut children = None;
+        let mut related = Vec::new();
+        while input.peek(token::Bracket) {
+            let content;
+            let brackets = bracketed!(content in input);
+            if is_related(&content) {
+                let relationship = content.parse()?;
+                content.parse::<Token![=>]>()?;
+                let template = content.parse()?;
+                related.push(Related {
+                    relationship,
+                    template,
+                });
+            } else if children.is_some() || !related.is_empty() {
+                return Err(syn::Error::new(
+                    brackets.span.join(),
+                    "children must come before related entities, and only once",
+                ));
+            } else {
+                children = Some(content.parse()?);
+            }
+        }
+
+        Ok(Body {
+            block,
+            observers,
+            context,
+            children: children.unwrap_or(Template { items: Vec::new() }),
+            related,
+        })
+    }
+}
+
+/// Returns true if the input starts with a path followed by `=>`, which begins a
+/// list of related entities rather than children.
+fn is_related(input: ParseStream) -> bool {
+    let fork = input.fork();
+    fork.parse::<Path>().is_ok() && fork.peek(Token![=>])
+}
+
+/// Collects the tokens from `begin` up to (but not including) `end`.
+fn tokens_between(begin: Cursor, end: Cursor) -> TokenStream {
+    let mut tokens = TokenStream::new();
+    let mut cursor = begin;
+    while cursor != end {
+        let Some((token, next)) = cursor.token_tree() else {
+            break;
+        };
+        tokens.extend([token]);
+        cursor = next;
+    }
+    tokens
+}
@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+
+use bevy_app::prelude::*;
+use bevy_asset::{
+    io::Reader, Asset, AssetApp, AssetEvent, AssetEvents, AssetId, AssetLoader, Assets, Handle,
+    LoadContext,
+};
+use bevy_ecs::{
+    event::{EventCursor, Events},
+    prelude::*,
+    reflect::AppTypeRegistry,
+};
+use bevy_reflect::{TypePath, TypeRegistryArc};
+
+use crate::{
+    BuildTemplate, DynamicTemplate, Template, TemplateParseError, TemplatePlugin, TemplateSystems,
+};
+
+/// A [`DynamicTemplate`] loaded from a file in the text format described in
+/// [`DynamicTemplate::parse`]. Files with the `.bsn` extension are loaded as
+/// template assets by the [`TemplateAssetPlugin`].
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct TemplateAsset {
+    /// The template parsed from the file.
+    pub template: DynamicTemplate,
+}
+
+/// Builds a [`TemplateAsset`] onto its entity once it has loaded, and rebuilds
+/// it whenever the asset changes, such as when the file is edited with hot
+/// reloading enabled.
+///
+/// Rebuilding reconciles the new template with the entities built last time in
+/// the same way as [`BuildTemplate::build`]: entities whose anchors still match
+/// are kept, along with their runtime state, such as components which weren't
+/// inserted by the template and the state of widgets spliced into it.
+///
+/// ```rust,no_run
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+///     commands.spawn(TemplateAssetRoot(asset_server.load("menu.bsn")));
+/// }
+/// ```
+#[derive(Component, Clone, Debug)]
+pub struct TemplateAssetRoot(pub Handle<TemplateAsset>);
+
+/// Loads [`TemplateAsset`]s from `.bsn` files, creating component values from
+/// the types registered in the [`AppTypeRegistry`].
+pub struct TemplateAssetLoader {
+    registry: TypeRegistryArc,
+}
+
+impl FromWorld for TemplateAssetLoader {
+    fn from_world(world: &mut World) -> Self {
+        TemplateAssetLoader {
+            registry: world.resource::<AppTypeRegistry>().0.clone(),
+        }
+    }
+}
+
+/// An error encountered while loading a [`TemplateAsset`].
+#[derive(Debug)]
+pub enum TemplateLoadError {
+    /// The file could not be read.
+    Io(std::io::Error),
+    /// The file isn't valid UTF-8.
+    Utf8(core::str::Utf8Error),
+    /// The file isn't a valid template.
+    Parse(TemplateParseError),
+}
+
+impl core::fmt::Display for TemplateLoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TemplateLoadError::Io(error) => write!(f, "could not read template: {error}"),
+            TemplateLoadError::Utf8(error) => write!(f, "template isn't valid UTF-8: {error}"),
+            TemplateLoadError::Parse(error) => write!(f, "could not parse template: {error}"),
+        }
+    }
+}
+
+impl core::error::Error for TemplateLoadError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            TemplateLoadError::Io(error) => Some(error),
+            TemplateLoadError::Utf8(error) => Some(error),
+            TemplateLoadError::Parse(error) => Some(error),
+        }
+    }
+}
+
+impl AssetLoader for TemplateAssetLoader {
+    type Asset = TemplateAsset;
+    type Settings = ();
+    type Error = TemplateLoadError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<TemplateAsset, TemplateLoadError> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(TemplateLoadError::Io)?;
+        let text = core::str::from_utf8(&bytes).map_err(TemplateLoadError::Utf8)?;
+        let template = DynamicTemplate::parse(text, &self.registry.read())
+            .map_err(TemplateLoadError::Parse)?;
+        Ok(TemplateAsset { template })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["bsn"]
+    }
+}
+
+/// Adds the [`TemplateAsset`] type and its loader, and builds
+/// [`TemplateAssetRoot`]s in [`TemplateSystems::Build`]. Adds the
+/// [`TemplatePlugin`] if it hasn't been added yet. Requires the
+/// `AssetPlugin`.
+pub struct TemplateAssetPlugin;
+
+impl Plugin for TemplateAssetPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<TemplatePlugin>() {
+            app.add_plugins(TemplatePlugin);
+        }
+        app.init_asset::<TemplateAsset>()
+            .init_asset_loader::<TemplateAssetLoader>()
+            .add_systems(
+                PostUpdate,
+                build_template_assets
+                    .after(AssetEvents)
+                    .in_set(TemplateSystems::Build),
+            );
+    }
+}
+
+/// Builds each [`TemplateAssetRoot`] whose handle has changed, or whose asset
+/// has been added, loaded or modified since this system last ran. Roots whose
+/// asset hasn't loaded yet are built once it has. This is added to
+/// [`PostUpdate`] by the [`TemplateAssetPlugin`].
+pub fn build_template_assets(
+    world: &mut World,
+    mut cursor: Local<EventCursor<AssetEvent<TemplateAsset>>>,
+) {
+    let changed = cursor
+        .read(world.resource::<Events<AssetEvent<TemplateAsset>>>())
+        .filter_map(|event| match event {
+            AssetEvent::Added { id }
+            | AssetEvent::LoadedWithDependencies { id }
+            | AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect::<HashSet<AssetId<TemplateAsset>>>();
+
+    let roots = world
+        .query::<(Entity, Ref<TemplateAssetRoot>)>()
+        .iter(world)
+        .filter(|(_, root)| root.is_changed() || changed.contains(&root.0.id()))
+        .map(|(entity, root)| (entity, root.0.id()))
+        .collect::<Vec<_>>();
+    for (entity, id) in roots {
+        let Some(asset) = world.resource::<Assets<TemplateAsset>>().get(id) else {
+            continue;
+        };
+        let template = asset.template.clone().into_iter().collect::<Template>();
+        template.build(world, entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{asset::AssetPlugin, prelude::*};
+
+    use crate::*;
+
+    #[derive(Component, Reflect, Default, Clone, PartialEq, Debug)]
+    #[reflect(Component, Default, PartialEq)]
+    struct Label(String);
+
+    #[derive(Component)]
+    struct Hovered;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, AssetPlugin::default(), TemplateAssetPlugin))
+            .register_type::<Label>();
+        app
+    }
+
+    fn parse(app: &App, text: &str) -> TemplateAsset {
+        let registry = app.world().resource::<AppTypeRegistry>().read();
+        TemplateAsset {
+            template: DynamicTemplate::parse(text, &registry).unwrap(),
+        }
+    }
+
+    #[test]
+    fn modified_assets_are_reconciled_in_place() {
+        let mut app = app();
+        let asset = parse(
+            &app,
+            r#"{ Label("menu") } [ a: { Label("a") }; b: { Label("b") }; ];"#,
+        );
+        let handle = app
+            .world_mut()
+            .resource_mut::<Assets<TemplateAsset>>()
+            .add(asset);
+        let root = app
+            .world_mut()
+            .spawn(TemplateAssetRoot(handle.clone()))
+            .id();
+
+        app.update();
+        let children = app.world().get::<Children>(root).unwrap().to_vec();
+        assert_eq!(children.len(), 2);
+        app.world_mut().entity_mut(children[1]).insert(Hovered);
+
+        // Editing the asset reorders and updates the existing entities rather
+        // than respawning them, so the runtime component is kept.
+        let edited = parse(
+            &app,
+            r#"{ Label("menu") } [ b: { Label("B") }; a: { Label("a") }; ];"#,
+        );
+        app.world_mut()
+            .resource_mut::<Assets<TemplateAsset>>()
+            .insert(&handle, edited);
+        app.update();
+        let reordered = app.world().get::<Children>(root).unwrap().to_vec();
+        assert_eq!(reordered, [children[1], children[0]]);
+        assert_eq!(
+            app.world().get::<Label>(children[1]),
+            Some(&Label("B".into()))
+        );
+        assert!(app.world().get::<Hovered>(children[1]).is_some());
+    }
+}
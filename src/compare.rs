@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use bevy_ecs::{
+    bundle::BundleEffect,
+    component::ComponentId,
+    prelude::*,
+    ptr::{OwningPtr, Ptr},
+};
+
+/// Compares a new component value with the live value of the same component.
+/// Drops the new value and returns `None` if they are equal, otherwise returns
+/// the new value so it can be inserted.
+///
+/// # Safety
+///
+/// Both pointers must point to values of the component type the function was
+/// registered for.
+type CompareFn = for<'a, 'b> unsafe fn(OwningPtr<'a>, Ptr<'b>) -> Option<OwningPtr<'a>>;
+
+/// The components which are compared with their live value when a template is
+/// built, and only written when they differ. Components are added to this with
+/// [`WorldTemplateExt::skip_unchanged`](crate::WorldTemplateExt::skip_unchanged).
+///
+/// Inserting a component marks it as changed even if the value is the same, so
+/// without this every `Changed<T>` filter matches every entity a template was
+/// built on, every time it is rebuilt.
+#[derive(Resource, Default)]
+pub struct SkipUnchanged(HashMap<ComponentId, CompareFn>);
+
+impl SkipUnchanged {
+    /// Opts the component `T` in to being compared with its live value. `id`
+    /// must be the id of `T` in the world this resource belongs to, so this is
+    /// only reachable through
+    /// [`WorldTemplateExt::skip_unchanged`](crate::WorldTemplateExt::skip_unchanged).
+    pub(crate) fn register<T: Component + PartialEq>(&mut self, id: ComponentId) {
+        self.0.insert(id, compare::<T>);
+    }
+
+    /// Returns true if the component is compared with its live value.
+    pub fn contains(&self, id: ComponentId) -> bool {
+        self.0.contains_key(&id)
+    }
+}
+
+/// The [`CompareFn`] for a specific component type.
+///
+/// # Safety
+///
+/// Both pointers must point to values of type `T`.
+unsafe fn compare<'a, T: Component + PartialEq>(
+    new: OwningPtr<'a>,
+    live: Ptr<'_>,
+) -> Option<OwningPtr<'a>> {
+    // SAFETY: The caller guarantees both pointers point to a `T`.
+    unsafe {
+        if new.as_ref().deref::<T>() == live.deref::<T>() {
+            new.drop_as::<T>();
+            None
+        } else {
+            Some(new)
+        }
+    }
+}
+
+/// Inserts a bundle on an entity, skipping the components registered with
+/// [`SkipUnchanged`] whose live value is equal to the new one. Everything else
+/// is overwritten like a plain insert.
+pub(crate) fn insert_changed<B: Bundle>(entity_mut: &mut EntityWorldMut, bundle: B) {
+    // The component ids of the bundle, in the order its values are visited.
+    let ids = entity_mut.world_scope(|world| {
+        let mut ids = Vec::new();
+        B::component_ids(&mut world.components_registrator(), &mut |id| ids.push(id));
+        ids
+    });
+
+    // Find the components which the entity already has and need comparing. If
+    // there are none, fall back to a plain insert.
+    let comparisons = match entity_mut.world().get_resource::<SkipUnchanged>() {
+        Some(skip_unchanged) => ids
+            .iter()
+            .map(|id| {
+                skip_unchanged
+                    .0
+                    .get(id)
+                    .copied()
+                    .filter(|_| entity_mut.contains_id(*id))
+            })
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+    if comparisons.iter().all(Option::is_none) {
+        entity_mut.insert(bundle);
+        return;
+    }
+
+    let mut index = 0;
+    let effect = bundle.get_components(&mut |_, new| {
+        let id = ids[index];
+        let compare = comparisons[index];
+        index += 1;
+
+        let new = match compare {
+            // SAFETY: The entity contains the component, and the compare
+            // function was registered for the same component type as `new`.
+            Some(compare) => unsafe { compare(new, entity_mut.get_by_id(id).unwrap()) },
+            None => Some(new),
+        };
+        if let Some(new) = new {
+            // SAFETY: The id comes from the same world, and `new` is a value
+            // of the component type it identifies.
+            unsafe {
+                entity_mut.insert_by_id(id, new);
+            }
+        }
+    });
+    effect.apply(entity_mut);
+}
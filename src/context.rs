@@ -0,0 +1,447 @@
+use core::any::{Any, TypeId};
+use std::{collections::HashMap, sync::Arc};
+
+use bevy_ecs::prelude::*;
+
+use crate::state::{rerender, with_scope};
+
+/// A type-erased context value.
+pub(crate) type ContextValue = Arc<dyn Any + Send + Sync>;
+
+/// A value provided by a [`Fragment`](crate::Fragment) to the entities built
+/// below it. These are usually created with `provide(...)` in the
+/// [`template`](crate::template) macro, and read with [`context`].
+///
+/// Values are identified by their type, and the nearest provider of a type
+/// wins. When a provider is rebuilt with a value which isn't equal to the
+/// previous one, every widget below it which read the value is rebuilt, even
+/// if it is inside a [`Memo`](crate::Memo) which was skipped.
+#[derive(Clone)]
+pub struct ContextProvider {
+    kind: TypeId,
+    value: ContextValue,
+    eq: fn(&dyn Any, &dyn Any) -> bool,
+}
+
+impl ContextProvider {
+    /// Creates a provider for a value.
+    pub fn new<T: Clone + PartialEq + Send + Sync + 'static>(value: T) -> Self {
+        ContextProvider {
+            kind: TypeId::of::<T>(),
+            value: Arc::new(value),
+            eq: |a, b| a.downcast_ref::<T>() == b.downcast_ref::<T>(),
+        }
+    }
+
+    /// Returns the type of the value.
+    pub fn kind(&self) -> TypeId {
+        self.kind
+    }
+
+    /// Returns the value, if it is of type `T`.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.value.downcast_ref()
+    }
+}
+
+/// The context values provided by the fragment built on an entity to the
+/// entities below it.
+#[derive(Component, Clone, Default)]
+pub struct Provided {
+    providers: Vec<ContextProvider>,
+}
+
+impl Provided {
+    /// Returns the value of type `T` provided by the entity, if there is one.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.providers
+            .iter()
+            .find(|provider| provider.kind == TypeId::of::<T>())?
+            .get()
+    }
+}
+
+/// The context values a widget read when it last rendered, so that it can be
+/// rebuilt when they change.
+#[derive(Component, Default)]
+pub(crate) struct Consumed(pub(crate) HashMap<TypeId, Option<ContextValue>>);
+
+/// The context values provided to the entity currently being built, from the
+/// root of the hierarchy down. This resource only exists while a template is
+/// being built, and can be read by [`Prototype`](crate::Prototype)s as they are
+/// built.
+#[derive(Resource, Default)]
+pub struct Contexts {
+    providers: Vec<ContextProvider>,
+    changed: Vec<(Entity, Vec<TypeId>)>,
+}
+
+impl Contexts {
+    /// Returns the nearest provided value of type `T`, if there is one.
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.providers
+            .iter()
+            .rev()
+            .find(|provider| provider.kind == TypeId::of::<T>())?
+            .get()
+    }
+
+    /// Returns the nearest provided value of each type.
+    pub(crate) fn nearest(&self) -> HashMap<TypeId, ContextValue> {
+        self.providers
+            .iter()
+            .map(|provider| (provider.kind, provider.value.clone()))
+            .collect()
+    }
+}
+
+/// Returns the value of type `T` provided by the nearest ancestor of the widget
+/// which is currently rendering, if there is one. The widget is rebuilt when
+/// the value changes.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// #[derive(Clone, PartialEq)]
+/// struct Theme {
+///     text: Color,
+/// }
+///
+/// #[derive(Clone)]
+/// struct Label(&'static str);
+///
+/// impl Widget for Label {
+///     fn render(&self) -> Template {
+///         let color = context::<Theme>().map_or(Color::WHITE, |theme| theme.text);
+///         template! { { (Text::new(self.0), TextColor(color)) }; }
+///     }
+/// }
+///
+/// template! {
+///     { Node::default() } provide(Theme { text: Color::BLACK }) [
+///         @widget { Label("Themed") };
+///     ];
+/// };
+/// ```
+///
+/// # Panics
+///
+/// Panics if no widget is rendering, for example when [`Widget::render`](crate::Widget::render)
+/// is called directly.
+pub fn context<T: Clone + Send + Sync + 'static>() -> Option<T> {
+    with_scope("context", |scope| {
+        let kind = TypeId::of::<T>();
+        let value = scope.contexts.get(&kind).cloned();
+        let result = value
+            .as_ref()
+            .and_then(|value| value.downcast_ref().cloned());
+        scope.consumed.insert(kind, value);
+        result
+    })
+}
+
+/// Returns the nearest value of each type provided by the ancestors of
+/// `entity`, not including the entity itself.
+fn ancestors(world: &World, entity: Entity) -> Vec<ContextProvider> {
+    let mut providers = Vec::new();
+    let mut current = entity;
+    while let Some(child_of) = world.get::<ChildOf>(current) {
+        current = child_of.parent();
+        if let Some(provided) = world.get::<Provided>(current) {
+            // Ancestors are visited from the nearest, so farther ones go underneath.
+            providers.splice(0..0, provided.providers.iter().cloned());
+        }
+    }
+    providers
+}
+
+/// Returns the nearest value of each type provided to `entity`, by the build in
+/// progress or otherwise by its ancestors.
+pub(crate) fn nearest(world: &World, entity: Option<Entity>) -> HashMap<TypeId, ContextValue> {
+    match world.get_resource::<Contexts>() {
+        Some(contexts) => contexts.nearest(),
+        None => Contexts {
+            providers: entity
+                .map(|entity| ancestors(world, entity))
+                .unwrap_or_default(),
+            changed: Vec::new(),
+        }
+        .nearest(),
+    }
+}
+
+/// Builds onto `entity` with the values provided by its ancestors. Once the
+/// outermost build is finished, the widgets which read a provided value which
+/// has changed are rebuilt.
+pub(crate) fn with_context<R>(
+    world: &mut World,
+    entity: Entity,
+    build: impl FnOnce(&mut World) -> R,
+) -> R {
+    if world.contains_resource::<Contexts>() {
+        return build(world);
+    }
+    let providers = ancestors(world, entity);
+    world.insert_resource(Contexts {
+        providers,
+        changed: Vec::new(),
+    });
+    let result = build(world);
+    let contexts = world.remove_resource::<Contexts>().unwrap_or_default();
+    for (provider, kinds) in contexts.changed {
+        rerender_consumers(world, provider, &kinds);
+    }
+    result
+}
+
+/// Provides the values of a fragment built on `entity` while its children are
+/// built, and stores them on the entity. Values which are equal to the previous
+/// ones are kept as they were, so that the widgets which read them don't need
+/// to be rebuilt.
+pub(crate) fn provide<R>(
+    world: &mut World,
+    entity: Entity,
+    providers: Vec<ContextProvider>,
+    build: impl FnOnce(&mut World) -> R,
+) -> R {
+    let previous = world
+        .get::<Provided>(entity)
+        .map(|provided| provided.providers.clone())
+        .unwrap_or_default();
+    if providers.is_empty() && previous.is_empty() {
+        return build(world);
+    }
+    // The first time the entity is built, there's nothing below it which could
+    // have read the previous values.
+    let rebuilt = world.get::<Children>(entity).is_some();
+
+    // Keep the previous values which are unchanged, and collect the types of
+    // the values which were added, changed or removed.
+    let mut changed = Vec::new();
+    let providers = providers
+        .into_iter()
+        .map(|provider| {
+            match previous
+                .iter()
+                .find(|previous| previous.kind == provider.kind)
+            {
+                Some(previous) if (provider.eq)(&*provider.value, &*previous.value) => {
+                    previous.clone()
+                }
+                _ => {
+                    changed.push(provider.kind);
+                    provider
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    changed.extend(
+        previous
+            .iter()
+            .filter(|previous| providers.iter().all(|p| p.kind != previous.kind))
+            .map(|previous| previous.kind),
+    );
+
+    let depth = world.get_resource_mut::<Contexts>().map(|mut contexts| {
+        let depth = contexts.providers.len();
+        contexts.providers.extend(providers.iter().cloned());
+        depth
+    });
+    let result = build(world);
+    if let Some(mut contexts) = world.get_resource_mut::<Contexts>() {
+        contexts.providers.truncate(depth.unwrap_or_default());
+        if !changed.is_empty() && rebuilt {
+            contexts.changed.push((entity, changed));
+        }
+    }
+
+    let mut entity_mut = world.entity_mut(entity);
+    if providers.is_empty() {
+        entity_mut.remove::<Provided>();
+    } else {
+        entity_mut.insert(Provided { providers });
+    }
+    result
+}
+
+/// Replaces a value provided by `entity`, and rebuilds the widgets below it
+/// which read the value if it changed.
+pub(crate) fn provide_value(world: &mut World, entity: Entity, provider: ContextProvider) {
+    let mut providers = world
+        .get::<Provided>(entity)
+        .map(|provided| provided.providers.clone())
+        .unwrap_or_default();
+    providers.retain(|previous| previous.kind != provider.kind);
+    providers.push(provider);
+    with_context(world, entity, |world| {
+        provide(world, entity, providers, |_| ())
+    });
+}
+
+/// Rebuilds the widgets below `provider` which read a value of one of the given
+/// types which is no longer the nearest one, stopping at other providers of the
+/// same type. Widgets are found by their [`Consumed`] component, which is
+/// removed once a widget is replaced by a fragment, so only entities which are
+/// still built by a widget are rebuilt.
+fn rerender_consumers(world: &mut World, provider: Entity, kinds: &[TypeId]) {
+    if world.get_entity(provider).is_err() {
+        return;
+    }
+    let mut providers = ancestors(world, provider);
+    providers.extend(
+        world
+            .get::<Provided>(provider)
+            .into_iter()
+            .flat_map(|p| p.providers.clone()),
+    );
+    let current = Contexts {
+        providers,
+        changed: Vec::new(),
+    }
+    .nearest();
+
+    let mut stack = children(world, provider)
+        .into_iter()
+        .map(|child| (child, current.clone()))
+        .collect::<Vec<_>>();
+    while let Some((entity, mut current)) = stack.pop() {
+        if world.get_entity(entity).is_err() {
+            continue;
+        }
+        let stale = world.get::<Consumed>(entity).is_some_and(|consumed| {
+            kinds
+                .iter()
+                .any(|kind| match (consumed.0.get(kind), current.get(kind)) {
+                    (None, _) => false,
+                    (Some(Some(read)), Some(value)) => !Arc::ptr_eq(read, value),
+                    (Some(read), value) => read.is_some() != value.is_some(),
+                })
+        });
+        if stale {
+            rerender(world, entity);
+        }
+
+        if let Some(provided) = world.get::<Provided>(entity) {
+            for provider in &provided.providers {
+                current.insert(provider.kind, provider.value.clone());
+            }
+        }
+        stack.extend(
+            children(world, entity)
+                .into_iter()
+                .map(|child| (child, current.clone())),
+        );
+    }
+}
+
+/// Returns the children of an entity.
+fn children(world: &World, entity: Entity) -> Vec<Entity> {
+    world
+        .get::<Children>(entity)
+        .map(|children| children.to_vec())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct Theme(&'static str);
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Label(String);
+
+    static RENDERS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Clone)]
+    struct Themed;
+
+    impl Widget for Themed {
+        fn render(&self) -> Template {
+            RENDERS.fetch_add(1, Ordering::Relaxed);
+            let theme = context::<Theme>().map_or("none", |theme| theme.0);
+            template! { { Label(theme.to_string()) }; }
+        }
+    }
+
+    fn page(theme: &'static str) -> Template {
+        template! {
+            { Node::default() } provide(Theme(theme)) [
+                @memo(()) {
+                    { Node::default() } [
+                        @widget { Themed };
+                    ];
+                };
+                { Node::default() } provide(Theme("nested")) [
+                    @widget { Themed };
+                ];
+            ];
+        }
+    }
+
+    fn labels(world: &mut World) -> Vec<String> {
+        let mut labels = world
+            .query::<&Label>()
+            .iter(world)
+            .map(|label| label.0.clone())
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn widgets_replaced_by_fragments_are_not_rebuilt() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! { { Node::default() } provide(Theme("a")) [ x: @widget { Themed }; ]; }
+            .build(&mut world, root);
+        let x = world.get::<Children>(root).unwrap()[0];
+        assert_eq!(world.get::<Label>(x), Some(&Label("a".into())));
+
+        template! { { Node::default() } provide(Theme("a")) [ x: { Label("plain".into()) }; ]; }
+            .build(&mut world, root);
+        assert_eq!(world.get::<Children>(root).unwrap()[0], x);
+        assert!(world.get::<Consumed>(x).is_none());
+
+        // The theme the old widget read changes, but the fragment is kept.
+        world.provide(root, Theme("b"));
+        assert_eq!(world.get::<Label>(x), Some(&Label("plain".into())));
+    }
+
+    #[test]
+    fn consumers_are_rebuilt_when_the_nearest_value_changes() {
+        let mut world = World::new();
+        world.build(template! { @widget { Themed }; });
+        assert_eq!(labels(&mut world), ["none"]);
+
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+        page("dark").build(&mut world, root);
+        assert_eq!(labels(&mut world), ["dark", "nested"]);
+        assert_eq!(
+            world.get::<Provided>(root).unwrap().get(),
+            Some(&Theme("dark"))
+        );
+
+        // The memo is skipped, but the widget inside it is still rebuilt.
+        page("light").build(&mut world, root);
+        assert_eq!(labels(&mut world), ["light", "nested"]);
+
+        // Nothing which read the theme changed, so nothing is rebuilt.
+        let renders = RENDERS.load(Ordering::Relaxed);
+        page("light").build(&mut world, root);
+        assert_eq!(RENDERS.load(Ordering::Relaxed), renders + 1);
+        assert_eq!(labels(&mut world), ["light", "nested"]);
+
+        // Changing the value directly only rebuilds the widget which reads it.
+        world.provide(root, Theme("dim"));
+        assert_eq!(RENDERS.load(Ordering::Relaxed), renders + 2);
+        assert_eq!(labels(&mut world), ["dim", "nested"]);
+    }
+}
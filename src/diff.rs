@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use bevy_ecs::{component::ComponentId, entity::hash_set::EntityHashSet, prelude::*};
+
+use crate::{
+    template::{match_children, Anchored},
+    Anchor, Prototype, Receipt, RelatedTemplate, Template, TemplateBuildError,
+};
+
+/// A dry run of building a template, listing the changes a build would make to
+/// the world without applying them.
+///
+/// Computing a diff only needs a `&World`, so it can be used for logging,
+/// testing, or previewing a rebuild in an editor before it happens.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// let mut world = World::new();
+/// let root = world.spawn_empty().id();
+/// template! { { Text::new("a") }; }.build(&mut world, root);
+///
+/// let template = template! { { Text::new("a") } [ { TextSpan::new("b") }; ]; };
+/// let diff = TemplateDiff::new(&world, root, &template).unwrap();
+/// assert_eq!(diff.reused().collect::<Vec<_>>(), [root]);
+/// assert_eq!(diff.spawned().count(), 1);
+/// ```
+#[derive(Clone, Default, Debug)]
+pub struct TemplateDiff {
+    /// The entities which would be built on, in the order they would be built.
+    pub entities: Vec<EntityDiff>,
+    /// The entities which would be despawned recursively, because they are no
+    /// longer part of the template. This includes observer entities.
+    pub despawned: Vec<Entity>,
+    /// The errors which would be reported while building.
+    pub errors: Vec<TemplateBuildError>,
+    /// The anchors leading from the root to the prototype currently being
+    /// diffed.
+    path: Vec<Anchor>,
+    /// The entities templates are currently being diffed onto, innermost last,
+    /// along with whether a template has been diffed or kept onto the same
+    /// entity one level deeper.
+    rooted: Vec<(Entity, bool)>,
+}
+
+/// The changes a build would make to a single entity. Part of a [`TemplateDiff`].
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct EntityDiff {
+    /// The anchors leading from the root of the template to this entity.
+    pub path: Vec<Anchor>,
+    /// The entity which would be re-used, or `None` if a new entity would be
+    /// spawned.
+    pub entity: Option<Entity>,
+    /// The components which the entity doesn't have yet and would be inserted.
+    /// Components it already has are overwritten in place.
+    pub inserted: Vec<ComponentId>,
+    /// The number of inserted components which have never been registered with
+    /// the world, and so have no [`ComponentId`] yet.
+    pub unregistered: usize,
+    /// The components inserted by the previous build which would be removed.
+    pub removed: Vec<ComponentId>,
+    /// The number of observers which would be spawned to watch the entity.
+    /// Observers which would be despawned are listed in
+    /// [`TemplateDiff::despawned`].
+    pub observers: usize,
+    /// Whether the existing children or related entities of the entity would be
+    /// re-ordered.
+    pub reordered: bool,
+}
+
+impl TemplateDiff {
+    /// Computes the changes building `template` onto `entity` would make. See
+    /// [`BuildTemplate::build`](crate::BuildTemplate::build) for how templates
+    /// are built.
+    pub fn new(
+        world: &World,
+        entity: Entity,
+        template: &Template,
+    ) -> Result<Self, TemplateBuildError> {
+        if world.get_entity(entity).is_err() {
+            return Err(TemplateBuildError::EntityNotFound { entity });
+        }
+
+        let mut diff = TemplateDiff::default();
+        diff.roots(world, Some(entity), template);
+        Ok(diff)
+    }
+
+    /// Records the changes building `template` onto `entity` with
+    /// [`BuildTemplate::build`](crate::BuildTemplate::build) would make.
+    /// `entity` is `None` if it would be freshly spawned.
+    ///
+    /// This is used to implement [`Prototype::diff`] for prototypes which build
+    /// a whole template onto their entity, such as widgets.
+    pub fn roots(
+        &mut self,
+        world: &World,
+        entity: Option<Entity>,
+        template: &[Box<dyn Prototype + Send + Sync>],
+    ) {
+        let Some((first, rest)) = template.split_first() else {
+            return;
+        };
+        let Some(entity) = entity else {
+            first.diff(world, None, self);
+            self.anchored(world, rest, None);
+            return;
+        };
+
+        // Templates built by a memo or widget which comes first record their
+        // roots one level deeper, see `build_roots`.
+        let depth = self
+            .rooted
+            .iter()
+            .filter(|(built, _)| *built == entity)
+            .count();
+        self.keep_nested_roots(entity);
+        self.rooted.push((entity, false));
+        first.diff(world, Some(entity), self);
+        let receipt = world.get::<Receipt>(entity);
+        let previous_roots = receipt.and_then(|receipt| receipt.roots.get(depth));
+        self.anchored(world, rest, previous_roots);
+
+        // The deeper roots are despawned if the first prototype no longer
+        // builds them.
+        let nested = self.rooted.pop().is_some_and(|(_, nested)| nested);
+        if !nested {
+            let stale = receipt
+                .into_iter()
+                .flat_map(|receipt| receipt.roots.iter().skip(depth + 1));
+            self.despawned.extend(
+                stale
+                    .flat_map(HashMap::values)
+                    .map(|anchored| anchored.entity)
+                    .filter(|entity| world.get_entity(*entity).is_ok()),
+            );
+        }
+    }
+
+    /// Keeps the roots recorded one level deeper than the template currently
+    /// being diffed onto `entity`, when a memo which comes first would skip its
+    /// build.
+    pub(crate) fn keep_nested_roots(&mut self, entity: Entity) {
+        if let Some(outer) = self
+            .rooted
+            .iter_mut()
+            .rev()
+            .find(|(built, _)| *built == entity)
+        {
+            outer.1 = true;
+        }
+    }
+
+    /// Returns the anchors leading from the root of the template to the
+    /// prototype currently being diffed.
+    pub fn path(&self) -> &[Anchor] {
+        &self.path
+    }
+
+    /// Returns the changes to entities which would be spawned.
+    pub fn spawned(&self) -> impl Iterator<Item = &EntityDiff> + '_ {
+        self.entities
+            .iter()
+            .filter(|entity_diff| entity_diff.entity.is_none())
+    }
+
+    /// Returns the existing entities which would be re-used.
+    pub fn reused(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities
+            .iter()
+            .filter_map(|entity_diff| entity_diff.entity)
+    }
+
+    /// Returns the existing entities whose children would be re-ordered.
+    pub fn reordered(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities
+            .iter()
+            .filter(|entity_diff| entity_diff.reordered)
+            .filter_map(|entity_diff| entity_diff.entity)
+    }
+
+    /// Records the changes building `template` as the children of `parent` would
+    /// make, and returns whether the existing children would be re-ordered.
+    /// `parent` is `None` if it would be freshly spawned.
+    ///
+    /// This is used to implement [`Prototype::diff`].
+    pub fn children(
+        &mut self,
+        world: &World,
+        parent: Option<Entity>,
+        template: &[Box<dyn Prototype + Send + Sync>],
+    ) -> bool {
+        let receipt = parent.and_then(|parent| world.get::<Receipt>(parent));
+        let previous_anchors = receipt.map(|receipt| &receipt.anchors);
+        let current = parent
+            .and_then(|parent| world.get::<Children>(parent))
+            .map(|children| children.to_vec())
+            .unwrap_or_default();
+        self.ordered(world, template, previous_anchors, &current)
+    }
+
+    /// Records the changes building a [`RelatedTemplate`] for `parent` would
+    /// make, and returns whether the existing related entities would be
+    /// re-ordered. `parent` is `None` if it would be freshly spawned.
+    ///
+    /// This is used to implement [`Prototype::diff`].
+    pub fn related(
+        &mut self,
+        world: &World,
+        parent: Option<Entity>,
+        related: &RelatedTemplate,
+    ) -> bool {
+        let receipt = parent.and_then(|parent| world.get::<Receipt>(parent));
+        let previous_anchors = receipt.and_then(|receipt| receipt.related.get(&related.kind()));
+        let current = parent
+            .map(|parent| related.current(world, parent))
+            .unwrap_or_default();
+        self.ordered(world, related.template(), previous_anchors, &current)
+    }
+
+    /// Records the entities built for anchors by a previous build as despawned.
+    pub(crate) fn despawned_anchors(
+        &mut self,
+        world: &World,
+        previous_anchors: &HashMap<Anchor, Anchored>,
+    ) {
+        self.anchored(world, &[], Some(previous_anchors));
+    }
+
+    /// Diffs a list of prototypes which are kept in template order, and returns
+    /// whether the `current` order of the re-used entities would change.
+    fn ordered(
+        &mut self,
+        world: &World,
+        template: &[Box<dyn Prototype + Send + Sync>],
+        previous_anchors: Option<&HashMap<Anchor, Anchored>>,
+        current: &[Entity],
+    ) -> bool {
+        let reused = self.anchored(world, template, previous_anchors);
+
+        // The entities are re-ordered if the re-used ones are currently in a
+        // different order than the template.
+        let reused_set = reused.iter().copied().collect::<EntityHashSet>();
+        let current = current
+            .iter()
+            .copied()
+            .filter(|entity| reused_set.contains(entity));
+        !current.eq(reused.iter().copied())
+    }
+
+    /// Diffs a list of prototypes against the entities built for their anchors
+    /// last time, and returns the entities which would be re-used in template
+    /// order.
+    fn anchored(
+        &mut self,
+        world: &World,
+        template: &[Box<dyn Prototype + Send + Sync>],
+        previous_anchors: Option<&HashMap<Anchor, Anchored>>,
+    ) -> Vec<Entity> {
+        let empty = HashMap::new();
+        let previous_anchors = previous_anchors.unwrap_or(&empty);
+        let matches = match_children(
+            world,
+            template,
+            previous_anchors,
+            &self.path,
+            &mut self.errors,
+        );
+
+        let mut reused = Vec::with_capacity(template.len());
+        for (child, child_match) in template.iter().zip(matches) {
+            reused.extend(child_match.entity);
+            self.path.push(child_match.anchor);
+            child.diff(world, child_match.entity, self);
+            self.path.pop();
+        }
+
+        // Every previous entity which isn't re-used would be despawned.
+        let reused_set = reused.iter().copied().collect::<EntityHashSet>();
+        self.despawned.extend(
+            previous_anchors
+                .values()
+                .map(|previous| previous.entity)
+                .filter(|entity| !reused_set.contains(entity) && world.get_entity(*entity).is_ok()),
+        );
+
+        reused
+    }
+}
@@ -0,0 +1,543 @@
+use core::any::TypeId;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use bevy_ecs::{
+    component::ComponentId,
+    prelude::*,
+    reflect::{AppTypeRegistry, ReflectComponent},
+};
+use bevy_reflect::{Reflect, ReflectFromReflect, TypeRegistry};
+
+use crate::{
+    template::{build_fragment, diff_fragment, report_error, BuildPath, BundleComponents},
+    Anchor, BuildTemplate, Key, Prototype, Receipt, Template, TemplateBuildError, TemplateDiff,
+};
+
+/// A template whose components are reflected values rather than Rust types, so
+/// that it can be created at runtime, for example by parsing the text format
+/// described in [`DynamicTemplate::parse`].
+///
+/// Dynamic templates turn into ordinary prototypes, so they can be built on
+/// their own or spliced into a [`template`](crate::template) with `@{ ... }`,
+/// next to fragments built from Rust types.
+///
+/// Building a dynamic template requires the world to have an
+/// [`AppTypeRegistry`] in which every component is registered with
+/// `#[reflect(Component)]`.
+#[derive(Clone, Default, Debug)]
+pub struct DynamicTemplate {
+    /// The items of the template, in order.
+    pub items: Vec<DynamicItem>,
+}
+
+/// An item of a [`DynamicTemplate`].
+#[derive(Clone, Debug)]
+pub enum DynamicItem {
+    /// A fragment of reflected components.
+    Fragment(DynamicFragment),
+    /// A reference to a template in the [`TemplateLibrary`].
+    Splice(DynamicSplice),
+}
+
+/// A fragment whose components are reflected values. It is built in the same
+/// way as a [`Fragment`](crate::Fragment), except that components which compare
+/// equal to the existing value with [`PartialReflect::reflect_partial_eq`] are
+/// not written again.
+///
+/// [`PartialReflect::reflect_partial_eq`]: bevy_reflect::PartialReflect::reflect_partial_eq
+#[derive(Clone, Debug)]
+pub struct DynamicFragment {
+    /// The anchor of the fragment, used to identify children across builds.
+    pub anchor: Option<Anchor>,
+    /// The components to be inserted on the entity. Each value must be of a
+    /// concrete component type, rather than a dynamic representation of one.
+    pub components: Vec<Arc<dyn Reflect>>,
+    /// The template for the children.
+    pub children: DynamicTemplate,
+}
+
+/// A reference to a template in the [`TemplateLibrary`] by name, which is
+/// looked up when the splice is built.
+///
+/// Unlike `@{ ... }` in the [`template`](crate::template) macro, the template
+/// isn't known until it is built, so it is built on an entity of its own in the
+/// same way as [`BuildTemplate::build`], like a [`Widget`](crate::Widget).
+#[derive(Clone, Debug)]
+pub struct DynamicSplice {
+    /// The anchor of the splice, used to identify it across builds.
+    pub anchor: Option<Anchor>,
+    /// The name of the template in the [`TemplateLibrary`].
+    pub name: String,
+}
+
+/// Named templates which can be spliced into a [`DynamicTemplate`] with
+/// `@name`. Insert this as a resource to make templates available.
+#[derive(Resource, Default, Clone)]
+pub struct TemplateLibrary {
+    templates: HashMap<String, Template>,
+}
+
+impl TemplateLibrary {
+    /// Adds a template under `name`, returning the template it replaced.
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        template: impl IntoIterator<Item = Box<dyn Prototype + Send + Sync>>,
+    ) -> Option<Template> {
+        self.templates
+            .insert(name.into(), template.into_iter().collect())
+    }
+
+    /// Returns the template added under `name`.
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.get(name)
+    }
+
+    /// Removes the template added under `name`, returning it.
+    pub fn remove(&mut self, name: &str) -> Option<Template> {
+        self.templates.remove(name)
+    }
+}
+
+impl DynamicTemplate {
+    /// Creates a template from the live hierarchy below `root`, walking its
+    /// [`Children`]. The template has a single fragment for `root`, with a
+    /// fragment for each child below it in order.
+    ///
+    /// Each fragment holds a copy of every reflected component of its entity,
+    /// other than [`ChildOf`] and [`Children`], so components which were added
+    /// at runtime are included, as are those which weren't inserted by a
+    /// template. Components which can't be cloned through reflection are left
+    /// out. Fragments are named after their [`Name`] component, or else the
+    /// anchor their entity was last built for, unless a sibling already has
+    /// the same name.
+    ///
+    /// ```rust
+    /// # use i_cant_believe_its_not_bsn::*;
+    /// # use bevy::prelude::*;
+    /// let mut world = World::new();
+    /// world.init_resource::<AppTypeRegistry>();
+    /// world.resource::<AppTypeRegistry>().write().register::<Name>();
+    ///
+    /// let root = world.spawn_empty().id();
+    /// template! {
+    ///     { Name::new("menu") } [
+    ///         play: { () };
+    ///     ];
+    /// }
+    /// .build(&mut world, root);
+    ///
+    /// let template = world.extract_template(root);
+    /// let text = template.to_text(&world.resource::<AppTypeRegistry>().read());
+    /// assert_eq!(
+    ///     text.unwrap(),
+    ///     "menu: { bevy_ecs::name::Name = \"menu\" } [\n    play: {};\n];\n",
+    /// );
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `root` does not exist, or if the world has no
+    /// [`AppTypeRegistry`].
+    pub fn extract(world: &World, root: Entity) -> Self {
+        let registry = world.resource::<AppTypeRegistry>().read();
+        let anchor = world
+            .get::<Name>(root)
+            .map(|name| Anchor::Named(name.to_string()));
+        let root = extract_fragment(world, &registry, root, anchor);
+        DynamicTemplate {
+            items: vec![DynamicItem::Fragment(root)],
+        }
+    }
+}
+
+/// Creates a fragment from an entity and the hierarchy below it. See
+/// [`DynamicTemplate::extract`].
+fn extract_fragment(
+    world: &World,
+    registry: &TypeRegistry,
+    entity: Entity,
+    anchor: Option<Anchor>,
+) -> DynamicFragment {
+    let entity_ref = world.entity(entity);
+    let hierarchy = [TypeId::of::<ChildOf>(), TypeId::of::<Children>()];
+    let components = entity_ref
+        .archetype()
+        .components()
+        .filter_map(|id| world.components().get_info(id)?.type_id())
+        .filter(|type_id| !hierarchy.contains(type_id))
+        .filter_map(|type_id| {
+            let value = registry
+                .get_type_data::<ReflectComponent>(type_id)?
+                .reflect(entity_ref)?;
+            let value = value.reflect_clone().ok().or_else(|| {
+                registry
+                    .get_type_data::<ReflectFromReflect>(type_id)?
+                    .from_reflect(value.as_partial_reflect())
+            })?;
+            Some(Arc::from(value))
+        })
+        .collect();
+
+    // Name the children after their `Name`, or else the anchor they were
+    // built for. Positional anchors are left out, since they are implied by
+    // the order of the children.
+    let anchors = world
+        .get::<Receipt>(entity)
+        .map(|receipt| {
+            receipt
+                .anchors()
+                .map(|(anchor, entity)| (entity, anchor))
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+    let mut names = HashSet::new();
+    let items = world
+        .get::<Children>(entity)
+        .into_iter()
+        .flatten()
+        .map(|&child| {
+            let name = match world.get::<Name>(child) {
+                Some(name) => Some(name.to_string()),
+                None => anchors
+                    .get(&child)
+                    .filter(|anchor| !anchor.is_positional())
+                    .map(ToString::to_string),
+            };
+            let anchor = name
+                .filter(|name| names.insert(name.clone()))
+                .map(Anchor::Named);
+            DynamicItem::Fragment(extract_fragment(world, registry, child, anchor))
+        })
+        .collect();
+
+    DynamicFragment {
+        anchor,
+        components,
+        children: DynamicTemplate { items },
+    }
+}
+
+impl Prototype for DynamicFragment {
+    fn name(&self) -> Option<String> {
+        self.anchor.as_ref().map(ToString::to_string)
+    }
+
+    fn anchor(&self) -> Option<Anchor> {
+        self.anchor.clone()
+    }
+
+    /// The kind of a dynamic fragment is the set of its component types, so
+    /// that an unnamed fragment whose components change is respawned, just
+    /// like a [`Fragment`](crate::Fragment) whose bundle type changes.
+    fn kind(&self) -> Key {
+        let mut types = self
+            .components
+            .iter()
+            .map(|component| component.reflect_type_info().type_id())
+            .collect::<Vec<_>>();
+        types.sort();
+        types.dedup();
+        Key::new(types)
+    }
+
+    fn build(self: Box<Self>, world: &mut World, entity: Entity) {
+        let DynamicFragment {
+            components,
+            children,
+            ..
+        } = *self;
+        build_fragment(
+            world,
+            entity,
+            children.into_iter().collect(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            |entity_mut| insert_reflected(entity_mut, &components),
+        );
+    }
+
+    fn diff(&self, world: &World, entity: Option<Entity>, diff: &mut TemplateDiff) {
+        let mut components = BundleComponents::default();
+        for component in &self.components {
+            match world
+                .components()
+                .get_id(component.reflect_type_info().type_id())
+            {
+                Some(id) => {
+                    components.ids.insert(id);
+                }
+                None => components.unregistered += 1,
+            }
+        }
+        let children = self.children.clone().into_iter().collect();
+        diff_fragment(world, entity, diff, components, &children, &[], &[]);
+    }
+}
+
+/// Inserts reflected components which have changed, returning the ids of every
+/// component. Components which aren't registered are reported as a
+/// [`TemplateBuildError`].
+fn insert_reflected(
+    entity_mut: &mut EntityWorldMut,
+    components: &[Arc<dyn Reflect>],
+) -> HashSet<ComponentId> {
+    let registry = entity_mut.world().resource::<AppTypeRegistry>().clone();
+    let registry = registry.read();
+    let mut ids = HashSet::with_capacity(components.len());
+    for component in components {
+        let type_id = component.reflect_type_info().type_id();
+        let Some(reflect_component) = registry.get_type_data::<ReflectComponent>(type_id) else {
+            let world = entity_mut.world();
+            let error = TemplateBuildError::UnregisteredComponent {
+                path: BuildPath::current(world),
+                type_path: component.reflect_type_path().to_string(),
+            };
+            report_error(world, error);
+            continue;
+        };
+        ids.insert(entity_mut.world_scope(|world| reflect_component.register_component(world)));
+
+        let unchanged = reflect_component
+            .reflect(entity_mut.as_readonly())
+            .and_then(|existing| existing.reflect_partial_eq(component.as_partial_reflect()))
+            .unwrap_or(false);
+        if !unchanged {
+            reflect_component.insert(entity_mut, component.as_partial_reflect(), &registry);
+        }
+    }
+    ids
+}
+
+impl Prototype for DynamicSplice {
+    fn name(&self) -> Option<String> {
+        self.anchor.as_ref().map(ToString::to_string)
+    }
+
+    fn anchor(&self) -> Option<Anchor> {
+        self.anchor.clone()
+    }
+
+    fn kind(&self) -> Key {
+        Key::new(TypeId::of::<DynamicSplice>())
+    }
+
+    fn build(self: Box<Self>, world: &mut World, entity: Entity) {
+        let template = world
+            .get_resource::<TemplateLibrary>()
+            .and_then(|library| library.get(&self.name))
+            .cloned();
+        match template {
+            Some(template) => template.build(world, entity),
+            None => {
+                let error = TemplateBuildError::UnknownTemplate {
+                    path: BuildPath::current(world),
+                    name: self.name,
+                };
+                report_error(world, error);
+            }
+        }
+    }
+
+    fn diff(&self, world: &World, entity: Option<Entity>, diff: &mut TemplateDiff) {
+        let template = world
+            .get_resource::<TemplateLibrary>()
+            .and_then(|library| library.get(&self.name));
+        match template {
+            Some(template) => diff.roots(world, entity, template),
+            None => diff.errors.push(TemplateBuildError::UnknownTemplate {
+                path: diff.path().to_vec(),
+                name: self.name.clone(),
+            }),
+        }
+    }
+}
+
+impl From<DynamicItem> for Box<dyn Prototype + Send + Sync> {
+    fn from(item: DynamicItem) -> Self {
+        match item {
+            DynamicItem::Fragment(fragment) => Box::new(fragment),
+            DynamicItem::Splice(splice) => Box::new(splice),
+        }
+    }
+}
+
+impl IntoIterator for DynamicTemplate {
+    type Item = Box<dyn Prototype + Send + Sync>;
+    type IntoIter = core::iter::Map<std::vec::IntoIter<DynamicItem>, fn(DynamicItem) -> Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter().map(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Reflect, Default, Clone, PartialEq, Debug)]
+    #[reflect(Component, Default, PartialEq)]
+    struct Label(String);
+
+    #[derive(Component, Reflect, Default, Clone, PartialEq, Debug)]
+    #[reflect(Component, Default, PartialEq)]
+    struct Count(u32);
+
+    #[derive(Resource, Default)]
+    struct Writes(usize);
+
+    fn world() -> World {
+        let mut world = World::new();
+        world.init_resource::<AppTypeRegistry>();
+        {
+            let mut registry = world.resource::<AppTypeRegistry>().write();
+            registry.register::<Label>();
+            registry.register::<Count>();
+        }
+        world
+    }
+
+    fn parse(world: &World, text: &str) -> DynamicTemplate {
+        DynamicTemplate::parse(text, &world.resource::<AppTypeRegistry>().read()).unwrap()
+    }
+
+    #[test]
+    fn dynamic_fragments_mix_with_fragments() {
+        let mut world = world();
+        world.init_resource::<Writes>();
+        world.add_observer(|_: Trigger<OnInsert, Count>, mut writes: ResMut<Writes>| {
+            writes.0 += 1;
+        });
+        let root = world.spawn_empty().id();
+
+        let mixed = |dynamic: DynamicTemplate| {
+            template! {
+                { Label("root".into()) } [
+                    @{ dynamic };
+                    { Label("c".into()) };
+                ];
+            }
+        };
+        let first = parse(&world, "a: { Label(\"a\"), Count(1) }; b: { Count(2) };");
+        mixed(first).build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(children.len(), 3);
+        assert_eq!(world.get::<Label>(children[0]), Some(&Label("a".into())));
+        assert_eq!(world.get::<Count>(children[0]), Some(&Count(1)));
+        assert_eq!(world.get::<Label>(children[2]), Some(&Label("c".into())));
+        assert_eq!(world.resource::<Writes>().0, 2);
+
+        // Unchanged values aren't written again, and stale components are
+        // removed.
+        let second = parse(&world, "b: { Count(2) }; a: { Count(1) };");
+        mixed(second).build(&mut world, root);
+        let reordered = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(reordered, [children[1], children[0], children[2]]);
+        assert_eq!(world.get::<Label>(children[0]), None);
+        assert_eq!(world.resource::<Writes>().0, 2);
+    }
+
+    #[test]
+    fn unnamed_dynamic_fragments_respawn_when_their_components_change() {
+        let mut world = world();
+        let root = world.spawn_empty().id();
+
+        let build = |world: &mut World, text: &str| {
+            let dynamic = parse(world, text);
+            template! { { Label("root".into()) } [ @{ dynamic }; ]; }.build(world, root);
+            world.get::<Children>(root).unwrap()[0]
+        };
+        let first = build(&mut world, "{ Count(1) };");
+
+        // The same components are re-used, while different ones respawn.
+        assert_eq!(build(&mut world, "{ Count(2) };"), first);
+        let second = build(&mut world, "{ Label(\"a\") };");
+        assert_ne!(second, first);
+        assert!(world.get_entity(first).is_err());
+        assert_eq!(world.get::<Count>(second), None);
+    }
+
+    #[test]
+    fn extracted_templates_rebuild_the_hierarchy() {
+        let mut world = world();
+        world
+            .resource::<AppTypeRegistry>()
+            .write()
+            .register::<Name>();
+        let root = world.spawn_empty().id();
+        template! {
+            { Count(0) } [
+                first: { Label("a".into()) };
+                { Label("b".into()) };
+                { (Name::new("first"), Label("c".into())) };
+            ];
+        }
+        .build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        world.entity_mut(children[1]).insert(Count(7));
+
+        // Names are taken from anchors and `Name`s, and duplicates are left
+        // out. Components added at runtime are included.
+        let template = world.extract_template(root);
+        let DynamicItem::Fragment(fragment) = &template.items[0] else {
+            panic!("expected a fragment");
+        };
+        let anchors = fragment
+            .children
+            .items
+            .iter()
+            .map(|item| match item {
+                DynamicItem::Fragment(fragment) => fragment.anchor.clone(),
+                DynamicItem::Splice(splice) => splice.anchor.clone(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(anchors, [Some(Anchor::Named("first".into())), None, None]);
+
+        let text = template
+            .to_text(&world.resource::<AppTypeRegistry>().read())
+            .unwrap();
+        let copy = world.spawn_empty().id();
+        parse(&world, &text)
+            .into_iter()
+            .collect::<Template>()
+            .build(&mut world, copy);
+        let copies = world.get::<Children>(copy).unwrap().to_vec();
+        assert_eq!(world.get::<Count>(copy), Some(&Count(0)));
+        assert_eq!(world.get::<Label>(copies[0]), Some(&Label("a".into())));
+        assert_eq!(world.get::<Count>(copies[1]), Some(&Count(7)));
+        assert_eq!(world.get::<Name>(copies[2]).unwrap().as_str(), "first");
+    }
+
+    #[test]
+    fn splices_build_library_templates() {
+        let mut world = world();
+        let mut library = TemplateLibrary::default();
+        library.insert("badge", template! { { Label("badge".into()) }; });
+        world.insert_resource(library);
+        let root = world.spawn_empty().id();
+
+        let template = parse(&world, "{ Count(0) } [ first: @badge; ];");
+        template
+            .into_iter()
+            .collect::<Template>()
+            .build(&mut world, root);
+        let badge = world.get::<Children>(root).unwrap()[0];
+        assert_eq!(world.get::<Label>(badge), Some(&Label("badge".into())));
+
+        let missing = parse(&world, "{ Count(0) } [ first: @missing; ];");
+        let diff = TemplateDiff::new(&world, root, &missing.into_iter().collect()).unwrap();
+        assert_eq!(
+            diff.errors,
+            [TemplateBuildError::UnknownTemplate {
+                path: vec![Anchor::Named("first".into())],
+                name: "missing".into(),
+            }]
+        );
+    }
+}
@@ -1,10 +1,61 @@
 #![doc = include_str!("../README.md")]
 
+// Lets the `template` macro refer to this crate by name from inside it.
+extern crate self as i_cant_believe_its_not_bsn;
+
+#[cfg(feature = "asset")]
+mod asset;
+#[cfg(feature = "asset")]
+pub use asset::*;
+
+mod compare;
+pub use compare::*;
+
+mod context;
+pub use context::*;
+
+mod diff;
+pub use diff::*;
+
+#[cfg(feature = "reflect")]
+mod dynamic;
+#[cfg(feature = "reflect")]
+pub use dynamic::*;
+
 mod hierarchy;
 pub use hierarchy::*;
 
 mod maybe;
 pub use maybe::*;
 
+mod memo;
+pub use memo::*;
+
+mod observer;
+pub use observer::*;
+
+mod plugin;
+pub use plugin::*;
+
+mod reference;
+pub use reference::*;
+
+mod related;
+pub use related::*;
+
+mod root;
+pub use root::*;
+
+mod state;
+pub use state::*;
+
 mod template;
 pub use template::*;
+
+#[cfg(feature = "reflect")]
+mod text;
+#[cfg(feature = "reflect")]
+pub use text::*;
+
+mod widget;
+pub use widget::*;
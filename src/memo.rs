@@ -0,0 +1,220 @@
+use core::{any::TypeId, hash::Hash};
+
+use bevy_ecs::prelude::*;
+
+use crate::{
+    context::with_context,
+    reference::{collect_references, record_references},
+    template::{build_roots, keep_nested_roots},
+    Anchor, Key, Prototype, Receipt, Template, TemplateDiff,
+};
+
+/// A memoized template, which is only built when its input changes. These are
+/// usually created with `@memo` in the [`template`](crate::template) macro.
+///
+/// The template is built on the memo's entity in the same way as
+/// [`BuildTemplate::build`](crate::BuildTemplate::build), and the input is
+/// stored in the entity's [`Receipt`]. When the memo is built again with an
+/// equal input, the build is skipped entirely and the existing entities are
+/// left untouched, even if the template is different.
+///
+/// The template is part of the template the memo is in, so references to names
+/// inside it resolve from outside, and the other way around. While the build is
+/// skipped, references keep resolving to the entities built last time.
+#[derive(Clone)]
+pub struct Memo {
+    /// The anchor of the memo, used to identify it across builds.
+    pub anchor: Option<Anchor>,
+    /// The input the template was created from.
+    pub input: Key,
+    /// The template to build when the input changes.
+    pub template: Template,
+}
+
+impl Memo {
+    /// Creates an unnamed memo from the input the template was created from.
+    pub fn new<T: Hash + Eq + Send + Sync + 'static>(input: T, template: Template) -> Self {
+        Memo {
+            anchor: None,
+            input: Key::new(input),
+            template,
+        }
+    }
+
+    /// Returns true if the memo was last built on `entity` with the same input.
+    fn unchanged(&self, world: &World, entity: Entity) -> bool {
+        world
+            .get::<Receipt>(entity)
+            .is_some_and(|receipt| receipt.memo.as_ref() == Some(&self.input))
+    }
+}
+
+impl Prototype for Memo {
+    fn name(&self) -> Option<String> {
+        self.anchor.as_ref().map(ToString::to_string)
+    }
+
+    fn anchor(&self) -> Option<Anchor> {
+        self.anchor.clone()
+    }
+
+    fn kind(&self) -> Key {
+        Key::new(TypeId::of::<Memo>())
+    }
+
+    fn build(self: Box<Self>, world: &mut World, entity: Entity) {
+        if self.unchanged(world, entity) {
+            keep_nested_roots(entity);
+            let names = world
+                .get::<Receipt>(entity)
+                .unwrap()
+                .memo_references
+                .clone();
+            record_references(world, &names);
+            return;
+        }
+        let Memo {
+            input, template, ..
+        } = *self;
+        let names = collect_references(world, |world| {
+            with_context(world, entity, |world| build_roots(world, entity, template));
+        });
+        let mut receipt = world.get_mut::<Receipt>(entity).unwrap();
+        receipt.memo = Some(input);
+        receipt.memo_references = names;
+    }
+
+    fn diff(&self, world: &World, entity: Option<Entity>, diff: &mut TemplateDiff) {
+        if let Some(entity) = entity.filter(|entity| self.unchanged(world, *entity)) {
+            diff.keep_nested_roots(entity);
+            return;
+        }
+        diff.roots(world, entity, &self.template);
+    }
+}
+
+impl IntoIterator for Memo {
+    type Item = Box<dyn Prototype + Send + Sync>;
+    type IntoIter = core::iter::Once<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        std::iter::once(Box::new(self) as Box<_>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Count(u32);
+
+    fn counter(input: u32, count: u32) -> Template {
+        template! {
+            @memo(input) {
+                { Count(count) } [
+                    { Count(count + 1) };
+                ];
+            };
+        }
+    }
+
+    #[test]
+    fn memos_which_come_first_keep_their_roots() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+        let page = |input: u32| {
+            template! {
+                @memo(input) { { Count(0) }; { Count(1) }; };
+                { Count(2) };
+            }
+        };
+
+        page(0).build(&mut world, root);
+        let entities = world.entities().len();
+        assert_eq!(entities, 3);
+
+        // The roots of the memo and of the template are re-used whether or not
+        // the memo is built again.
+        for input in [1, 1, 0, 1] {
+            page(input).build(&mut world, root);
+            assert_eq!(world.entities().len(), entities);
+            let diff = TemplateDiff::new(&world, root, &page(input + 1)).unwrap();
+            assert_eq!(diff.spawned().count(), 0);
+            assert!(diff.despawned.is_empty());
+        }
+
+        // Once the memo is gone, its roots are despawned.
+        let page = template! { { Count(0) }; { Count(2) }; };
+        let diff = TemplateDiff::new(&world, root, &page).unwrap();
+        assert_eq!(diff.despawned.len(), 1);
+        page.build(&mut world, root);
+        assert_eq!(world.entities().len(), 2);
+    }
+
+    #[test]
+    fn memos_skip_unchanged_inputs() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        counter(1, 10).build(&mut world, root);
+        assert_eq!(world.get::<Count>(root), Some(&Count(10)));
+        let child = world.get::<Children>(root).unwrap()[0];
+
+        // The input is the same, so the new count is ignored.
+        counter(1, 20).build(&mut world, root);
+        assert_eq!(world.get::<Count>(root), Some(&Count(10)));
+        assert_eq!(world.get::<Count>(child), Some(&Count(11)));
+        let diff = TemplateDiff::new(&world, root, &counter(1, 20)).unwrap();
+        assert!(diff.entities.is_empty());
+
+        counter(2, 20).build(&mut world, root);
+        assert_eq!(world.get::<Count>(root), Some(&Count(20)));
+        assert_eq!(world.get::<Children>(root).unwrap()[0], child);
+        assert_eq!(world.get::<Count>(child), Some(&Count(21)));
+    }
+
+    #[test]
+    fn memos_are_rebuilt_after_other_builds() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let memo = || template! { x: @memo(1) { { Count(1) }; }; };
+        memo().build(&mut world, root);
+        assert_eq!(world.get::<Count>(root), Some(&Count(1)));
+
+        // A fragment built on the same entity replaces the memo, so building
+        // the memo again with the same input isn't skipped.
+        template! { x: { Count(2) }; }.build(&mut world, root);
+        assert_eq!(world.get::<Count>(root), Some(&Count(2)));
+        memo().build(&mut world, root);
+        assert_eq!(world.get::<Count>(root), Some(&Count(1)));
+    }
+
+    #[test]
+    fn named_memos_are_matched_by_name() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let list = |names: &[&'static str]| {
+            let mut template = template! { { Count(0) }; };
+            for name in names {
+                template.push(Box::new(Memo {
+                    anchor: Some(Anchor::Named(name.to_string())),
+                    ..Memo::new(*name, template! { { Count(name.len() as u32) }; })
+                }));
+            }
+            template! { { Node::default() } [ @{ template }; ]; }
+        };
+
+        list(&["a", "bb"]).build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+
+        list(&["bb", "a"]).build(&mut world, root);
+        let reordered = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(reordered, [children[0], children[2], children[1]]);
+        assert_eq!(world.get::<Count>(reordered[1]), Some(&Count(2)));
+    }
+}
@@ -0,0 +1,259 @@
+use core::{any::TypeId, hash::Hash};
+use std::sync::Arc;
+
+use bevy_ecs::{prelude::*, system::IntoObserverSystem};
+
+use crate::{template::Anchored, Key};
+
+/// An observer attached to a [`Fragment`](crate::Fragment), which watches the
+/// entity the fragment is built on. These are usually created with
+/// `on(...)` in the [`template`](crate::template) macro.
+///
+/// Observers are matched across builds by the type of their system, so an
+/// observer is only spawned the first time a fragment is built, and is
+/// despawned once the fragment no longer has an observer of that type. Since
+/// the observer that was spawned keeps running, a closure keeps the values it
+/// captured in the first build, even if later builds capture different ones.
+/// Give the observer a key with [`FragmentObserver::with_key`] to have it
+/// respawned whenever the key changes.
+#[derive(Clone)]
+pub struct FragmentObserver {
+    kind: TypeId,
+    key: Option<Key>,
+    observer: Arc<dyn Fn() -> Observer + Send + Sync>,
+}
+
+impl FragmentObserver {
+    /// Creates an observer from a system, in the same way as
+    /// [`Observer::new`]. The system must be `Clone` so that the fragment can
+    /// be cloned.
+    pub fn new<E: Event, B: Bundle, M, I>(system: I) -> Self
+    where
+        I: IntoObserverSystem<E, B, M> + Clone + Sync,
+    {
+        FragmentObserver {
+            kind: TypeId::of::<I>(),
+            key: None,
+            observer: Arc::new(move || Observer::new(system.clone())),
+        }
+    }
+
+    /// Keys the observer on a value, such as the values its system captures.
+    /// The observer is only re-used by a later build with an equal key, and is
+    /// respawned otherwise.
+    pub fn with_key<T: Hash + Eq + Send + Sync + 'static>(mut self, key: T) -> Self {
+        self.key = Some(Key::new(key));
+        self
+    }
+
+    /// Returns the type of the observer system, which identifies the observer
+    /// across builds.
+    pub fn kind(&self) -> TypeId {
+        self.kind
+    }
+
+    /// Returns the key of the observer, if it has one. See
+    /// [`FragmentObserver::with_key`].
+    pub fn key(&self) -> Option<&Key> {
+        self.key.as_ref()
+    }
+
+    /// Creates a new [`Observer`] running the system. It doesn't watch any
+    /// entities yet.
+    pub fn observer(&self) -> Observer {
+        (self.observer)()
+    }
+}
+
+/// Matches a list of observers to the observer entities spawned for the same
+/// kind of system and key last time. Returns the matched entity for each
+/// observer, along with the previous observer entities which weren't matched.
+fn match_observers(
+    world: &World,
+    observers: &[FragmentObserver],
+    mut previous: Vec<(Option<Key>, Anchored)>,
+) -> (Vec<Option<Entity>>, Vec<Entity>) {
+    previous.retain(|(_, anchored)| world.get_entity(anchored.entity).is_ok());
+    let matches = observers
+        .iter()
+        .map(|observer| {
+            let kind = Key::new(observer.kind);
+            let index = previous
+                .iter()
+                .position(|(key, anchored)| anchored.kind == kind && *key == observer.key)?;
+            Some(previous.remove(index).1.entity)
+        })
+        .collect();
+    let stale = previous
+        .into_iter()
+        .map(|(_, anchored)| anchored.entity)
+        .collect();
+    (matches, stale)
+}
+
+/// Spawns the observers of a fragment watching `entity`, re-using the observers
+/// spawned by the previous build where possible, and despawning the ones which
+/// are no longer needed.
+///
+/// Returns the observer entities, to be stored in the receipt.
+pub(crate) fn build_observers(
+    world: &mut World,
+    entity: Entity,
+    observers: Vec<FragmentObserver>,
+    previous: Vec<(Option<Key>, Anchored)>,
+) -> Vec<(Option<Key>, Anchored)> {
+    let (matches, stale) = match_observers(world, &observers, previous);
+    for observer in stale {
+        world.despawn(observer);
+    }
+
+    let observers = observers
+        .into_iter()
+        .zip(matches)
+        .map(|(observer, entity_match)| {
+            let anchored = Anchored {
+                entity: entity_match
+                    .unwrap_or_else(|| world.spawn(observer.observer().with_entity(entity)).id()),
+                kind: Key::new(observer.kind),
+            };
+            (observer.key, anchored)
+        })
+        .collect();
+    world.flush();
+    observers
+}
+
+/// Returns the number of observers which building a fragment's observers would
+/// spawn, along with the observer entities it would despawn. See
+/// [`build_observers`].
+pub(crate) fn diff_observers(
+    world: &World,
+    observers: &[FragmentObserver],
+    previous: Vec<(Option<Key>, Anchored)>,
+) -> (usize, Vec<Entity>) {
+    let (matches, stale) = match_observers(world, observers, previous);
+    let spawned = matches.iter().filter(|entity| entity.is_none()).count();
+    (spawned, stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Clone)]
+    struct A;
+
+    #[derive(Event)]
+    struct Ping;
+
+    #[derive(Resource, Default)]
+    struct Pings(Vec<&'static str>);
+
+    fn ping_a(_: Trigger<Ping>, mut pings: ResMut<Pings>) {
+        pings.0.push("a");
+    }
+
+    fn ping_b(_: Trigger<Ping>, mut pings: ResMut<Pings>) {
+        pings.0.push("b");
+    }
+
+    #[test]
+    fn observers_are_spawned_once() {
+        let mut world = World::new();
+        world.init_resource::<Pings>();
+        let root = world.spawn_empty().id();
+
+        let template = template! { { A } on(ping_a); };
+        template.clone().build(&mut world, root);
+        let observers = world
+            .get::<Receipt>(root)
+            .unwrap()
+            .observers()
+            .collect::<Vec<_>>();
+        assert_eq!(observers.len(), 1);
+
+        template.build(&mut world, root);
+        let rebuilt = world
+            .get::<Receipt>(root)
+            .unwrap()
+            .observers()
+            .collect::<Vec<_>>();
+        assert_eq!(rebuilt, observers);
+
+        world.trigger_targets(Ping, root);
+        assert_eq!(world.resource::<Pings>().0, ["a"]);
+    }
+
+    #[test]
+    fn observers_are_replaced_when_handlers_change() {
+        let mut world = World::new();
+        world.init_resource::<Pings>();
+        let root = world.spawn_empty().id();
+
+        template! { { A } on(ping_a) on(ping_b); }.build(&mut world, root);
+        let observers = world
+            .get::<Receipt>(root)
+            .unwrap()
+            .observers()
+            .collect::<Vec<_>>();
+
+        let template = template! {
+            { A } on(ping_b) on(|_: Trigger<Ping>, mut pings: ResMut<Pings>| {
+                pings.0.push("closure");
+            });
+        };
+        let diff = TemplateDiff::new(&world, root, &template).unwrap();
+        assert_eq!(diff.entities[0].observers, 1);
+        assert_eq!(diff.despawned, [observers[0]]);
+
+        template.build(&mut world, root);
+        let rebuilt = world
+            .get::<Receipt>(root)
+            .unwrap()
+            .observers()
+            .collect::<Vec<_>>();
+        assert_eq!(rebuilt[0], observers[1]);
+        assert!(world.get_entity(observers[0]).is_err());
+
+        world.trigger_targets(Ping, root);
+        let mut pings = world.resource::<Pings>().0.clone();
+        pings.sort();
+        assert_eq!(pings, ["b", "closure"]);
+    }
+
+    #[test]
+    fn keyed_observers_are_respawned_when_their_keys_change() {
+        let mut world = World::new();
+        world.init_resource::<Pings>();
+        let root = world.spawn_empty().id();
+
+        let button = |label: &'static str| {
+            template! {
+                { A } on(move |_: Trigger<Ping>, mut pings: ResMut<Pings>| {
+                    pings.0.push(label);
+                } => key(label));
+            }
+        };
+        let observers = |world: &World| {
+            world
+                .get::<Receipt>(root)
+                .unwrap()
+                .observers()
+                .collect::<Vec<_>>()
+        };
+
+        button("a").build(&mut world, root);
+        let first = observers(&world);
+        button("a").build(&mut world, root);
+        assert_eq!(observers(&world), first);
+
+        button("b").build(&mut world, root);
+        assert_ne!(observers(&world), first);
+        assert!(world.get_entity(first[0]).is_err());
+
+        world.trigger_targets(Ping, root);
+        assert_eq!(world.resource::<Pings>().0, ["b"]);
+    }
+}
@@ -0,0 +1,293 @@
+use bevy_app::prelude::*;
+use bevy_ecs::{entity::hash_map::EntityHashMap, prelude::*};
+
+use crate::{build_template_roots, BuildTemplate, Template, TemplateRoot};
+
+/// Schedules template builds in [`PostUpdate`], in the [`TemplateSystems::Build`]
+/// system set:
+/// + [`TemplateRoot`]s are rebuilt whenever their inputs change.
+/// + Templates built with commands, such as
+///   [`CommandsTemplateExt::build`](crate::CommandsTemplateExt::build), are
+///   queued in [`PendingBuilds`] instead of being built when the commands are
+///   applied. Only the last template queued for each entity in a frame is built.
+///
+/// Templates built directly with [`BuildTemplate::build`] aren't affected.
+pub struct TemplatePlugin;
+
+impl Plugin for TemplatePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PendingBuilds>()
+            .configure_sets(PostUpdate, TemplateSystems::Build)
+            .add_systems(
+                PostUpdate,
+                (build_template_roots, build_pending_templates)
+                    .chain()
+                    .in_set(TemplateSystems::Build),
+            );
+    }
+}
+
+/// The system sets added by the [`TemplatePlugin`]. Order UI layout after
+/// [`TemplateSystems::Build`] so that it sees the built entities.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::{prelude::*, ui::UiSystem};
+/// App::new().add_plugins(TemplatePlugin).configure_sets(
+///     PostUpdate,
+///     TemplateSystems::Build.before(UiSystem::Layout),
+/// );
+/// ```
+#[derive(SystemSet, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum TemplateSystems {
+    /// Builds template roots and the templates queued by commands.
+    Build,
+}
+
+/// The templates queued by commands to be built in [`TemplateSystems::Build`],
+/// by entity, in the order they were first queued. This resource is added by
+/// the [`TemplatePlugin`]; without it, commands build templates immediately.
+#[derive(Resource, Default)]
+pub struct PendingBuilds {
+    builds: EntityHashMap<Template>,
+    order: Vec<Entity>,
+}
+
+impl PendingBuilds {
+    /// Queues a template to be built onto `entity`, replacing any template
+    /// already queued for it.
+    pub fn queue(&mut self, entity: Entity, template: Template) {
+        if self.builds.insert(entity, template).is_none() {
+            self.order.push(entity);
+        }
+    }
+
+    /// Returns true if a template is queued for `entity`.
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.builds.contains_key(&entity)
+    }
+
+    /// Returns the number of entities with a queued template.
+    pub fn len(&self) -> usize {
+        self.builds.len()
+    }
+
+    /// Returns true if no templates are queued.
+    pub fn is_empty(&self) -> bool {
+        self.builds.is_empty()
+    }
+}
+
+/// Builds the templates queued in [`PendingBuilds`]. Entities which have been
+/// despawned since their template was queued are skipped: the command which
+/// queued the template has already checked that the entity existed, and
+/// reported an error through its handler if it didn't.
+pub fn build_pending_templates(world: &mut World) {
+    let Some(mut pending) = world.get_resource_mut::<PendingBuilds>() else {
+        return;
+    };
+    let mut builds = core::mem::take(&mut pending.builds);
+    for entity in core::mem::take(&mut pending.order) {
+        let Some(template) = builds.remove(&entity) else {
+            continue;
+        };
+        if world.get_entity(entity).is_ok() {
+            template.build(world, entity);
+        }
+    }
+}
+
+pub trait AppTemplateExt {
+    /// Spawns a [`TemplateRoot`] for a system returning a [`Template`], which is
+    /// built onto the root entity the first time the app updates and rebuilt
+    /// whenever the system's inputs change. Adds the [`TemplatePlugin`] if it
+    /// hasn't been added yet.
+    ///
+    /// ```rust
+    /// # use i_cant_believe_its_not_bsn::*;
+    /// # use bevy::prelude::*;
+    /// #[derive(Resource)]
+    /// struct Score(u32);
+    ///
+    /// App::new()
+    ///     .insert_resource(Score(0))
+    ///     .add_template_root(|score: Res<Score>| {
+    ///         template! {
+    ///             { Text::new(format!("Score: {}", score.0)) };
+    ///         }
+    ///     });
+    /// ```
+    fn add_template_root<M>(&mut self, system: impl IntoSystem<(), Template, M>) -> &mut Self;
+}
+
+impl AppTemplateExt for App {
+    fn add_template_root<M>(&mut self, system: impl IntoSystem<(), Template, M>) -> &mut Self {
+        if !self.is_plugin_added::<TemplatePlugin>() {
+            self.add_plugins(TemplatePlugin);
+        }
+        self.world_mut().spawn(TemplateRoot::new(system));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Resource)]
+    struct Score(u32);
+
+    #[derive(Resource, Default)]
+    struct Adds(usize);
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Item(&'static str);
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Label(String);
+
+    #[derive(Component)]
+    #[component(storage = "SparseSet")]
+    struct Selected(bool);
+
+    fn labels(app: &mut App) -> Vec<String> {
+        let mut labels = app
+            .world_mut()
+            .query::<&Label>()
+            .iter(app.world())
+            .map(|label| label.0.clone())
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn roots_rebuild_when_resources_change() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut app = App::new();
+        app.insert_resource(Score(0)).add_template_root({
+            let runs = runs.clone();
+            move |score: Res<Score>| {
+                runs.fetch_add(1, Ordering::Relaxed);
+                template! { { Label(format!("score {}", score.0)) }; }
+            }
+        });
+
+        app.update();
+        app.update();
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert_eq!(labels(&mut app), ["score 0"]);
+
+        app.world_mut().resource_mut::<Score>().0 = 5;
+        app.update();
+        app.update();
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+        assert_eq!(labels(&mut app), ["score 5"]);
+    }
+
+    #[test]
+    fn roots_rebuild_when_components_change() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut app = App::new();
+        let sword = app.world_mut().spawn(Item("sword")).id();
+        app.add_template_root({
+            let runs = runs.clone();
+            move |items: Query<&Item>| {
+                runs.fetch_add(1, Ordering::Relaxed);
+                let count = items.iter().count();
+                template! { { Label(format!("{count} items")) }; }
+            }
+        });
+
+        app.update();
+        app.update();
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert_eq!(labels(&mut app), ["1 items"]);
+
+        app.world_mut().spawn(Item("shield"));
+        app.update();
+        assert_eq!(labels(&mut app), ["2 items"]);
+
+        app.world_mut().despawn(sword);
+        app.update();
+        app.update();
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        assert_eq!(labels(&mut app), ["1 items"]);
+    }
+
+    #[test]
+    fn roots_rebuild_when_component_values_change() {
+        let mut app = App::new();
+        let sword = app.world_mut().spawn((Item("sword"), Selected(false))).id();
+        app.world_mut().spawn(Item("shield"));
+        app.add_template_root(|items: Query<(&Item, Option<&Selected>)>| {
+            let mut names = items
+                .iter()
+                .filter(|(_, selected)| selected.is_none_or(|selected| selected.0))
+                .map(|(item, _)| item.0)
+                .collect::<Vec<_>>();
+            names.sort();
+            template! { { Label(names.join(", ")) }; }
+        });
+
+        app.update();
+        assert_eq!(labels(&mut app), ["shield"]);
+
+        // Changes to both table and sparse set components are detected.
+        app.world_mut().get_mut::<Selected>(sword).unwrap().0 = true;
+        app.update();
+        assert_eq!(labels(&mut app), ["shield, sword"]);
+
+        app.world_mut().get_mut::<Item>(sword).unwrap().0 = "axe";
+        app.update();
+        assert_eq!(labels(&mut app), ["axe, shield"]);
+    }
+
+    #[test]
+    fn queued_builds_keep_the_last_template() {
+        let mut app = App::new();
+        app.add_plugins(TemplatePlugin)
+            .init_resource::<Adds>()
+            .add_observer(|_: Trigger<OnAdd, Item>, mut adds: ResMut<Adds>| adds.0 += 1);
+        let root = app.world_mut().spawn_empty().id();
+
+        let mut commands = app.world_mut().commands();
+        commands.build_to(template! { { Item("dropped") }; }, root);
+        commands.build_to(template! { { Label("kept".to_string()) }; }, root);
+        app.world_mut().flush();
+        assert!(app.world().resource::<PendingBuilds>().contains(root));
+        assert!(app.world().get::<Label>(root).is_none());
+
+        app.update();
+        assert!(app.world().resource::<PendingBuilds>().is_empty());
+        assert_eq!(labels(&mut app), ["kept"]);
+        assert_eq!(app.world().resource::<Adds>().0, 0);
+    }
+
+    #[test]
+    fn despawned_entities_are_skipped() {
+        let mut app = App::new();
+        app.add_plugins(TemplatePlugin);
+        let root = app.world_mut().spawn_empty().id();
+
+        app.world_mut().commands().queue_handled(
+            BuildTemplateCommand::new(template! { { Item("lost") }; }, root),
+            bevy::ecs::error::ignore,
+        );
+        app.world_mut().flush();
+        app.world_mut().despawn(root);
+        app.update();
+        assert!(app.world().resource::<PendingBuilds>().is_empty());
+        assert_eq!(
+            app.world_mut().query::<&Item>().iter(app.world()).count(),
+            0
+        );
+    }
+}
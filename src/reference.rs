@@ -0,0 +1,321 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bevy_ecs::prelude::*;
+
+use crate::{
+    compare::insert_changed,
+    template::{report_error, BuildPath},
+    Anchor, TemplateBuildError,
+};
+
+/// The entities built for the statically named prototypes of a template, which
+/// `#name` references in the [`template`](crate::template) macro resolve to.
+#[derive(Clone, Default, Debug)]
+pub struct References {
+    names: HashMap<String, Entity>,
+}
+
+impl References {
+    /// Returns the entity built for the prototype with the given static name,
+    /// or [`Entity::PLACEHOLDER`] if there isn't one.
+    pub fn get(&self, name: &str) -> Entity {
+        self.names.get(name).copied().unwrap_or(Entity::PLACEHOLDER)
+    }
+}
+
+/// A bundle which refers to other entities in the same template, and so can't
+/// be created until the template has been built. Used by
+/// [`Fragment::resolve`](crate::Fragment::resolve).
+pub struct ResolveBundle<B> {
+    names: Vec<String>,
+    resolve: Arc<dyn Fn(&References) -> B + Send + Sync>,
+}
+
+impl<B> ResolveBundle<B> {
+    /// Creates a bundle from a function, which is called with the entities
+    /// built for the given names once they are known.
+    pub fn new(
+        names: impl IntoIterator<Item = impl Into<String>>,
+        resolve: impl Fn(&References) -> B + Send + Sync + 'static,
+    ) -> Self {
+        ResolveBundle {
+            names: names.into_iter().map(Into::into).collect(),
+            resolve: Arc::new(resolve),
+        }
+    }
+
+    /// Returns the names the bundle refers to.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Creates the bundle.
+    pub fn resolve(&self, references: &References) -> B {
+        (self.resolve)(references)
+    }
+}
+
+impl<B> Clone for ResolveBundle<B> {
+    fn clone(&self) -> Self {
+        ResolveBundle {
+            names: self.names.clone(),
+            resolve: self.resolve.clone(),
+        }
+    }
+}
+
+/// The named entities built so far by the template currently being built, and
+/// the bundles waiting for them.
+#[derive(Resource, Default)]
+pub(crate) struct PendingReferences {
+    names: HashMap<String, Vec<Entity>>,
+    bundles: Vec<PendingBundle>,
+}
+
+/// Inserts a resolved bundle on the entity it belongs to.
+type InsertFn = Box<dyn FnOnce(&mut World, &References) + Send + Sync>;
+
+/// A bundle waiting to be inserted once the references are known.
+struct PendingBundle {
+    path: Vec<Anchor>,
+    names: Vec<String>,
+    insert: InsertFn,
+}
+
+/// Records the entity built for an anchor, so that references to its name can
+/// be resolved. Only static names can be referred to, including those inside
+/// scopes.
+pub(crate) fn record_reference(world: &mut World, anchor: &Anchor, entity: Entity) {
+    let mut anchor = anchor;
+    while let Anchor::Scoped(_, _, inner) = anchor {
+        anchor = inner;
+    }
+    let Anchor::Named(name) = anchor else {
+        return;
+    };
+    if let Some(mut pending) = world.get_resource_mut::<PendingReferences>() {
+        pending.names.entry(name.clone()).or_default().push(entity);
+    }
+}
+
+/// Calls `build`, returning the named entities it recorded. They are still
+/// recorded in the template currently being built, so that references from
+/// outside can resolve to them.
+pub(crate) fn collect_references(
+    world: &mut World,
+    build: impl FnOnce(&mut World),
+) -> Vec<(String, Entity)> {
+    let outer = world
+        .get_resource_mut::<PendingReferences>()
+        .map(|mut pending| core::mem::take(&mut pending.names));
+    build(world);
+    let Some(mut pending) = world.get_resource_mut::<PendingReferences>() else {
+        return Vec::new();
+    };
+    let names = core::mem::replace(&mut pending.names, outer.unwrap_or_default());
+    let names = names
+        .into_iter()
+        .flat_map(|(name, entities)| {
+            entities
+                .into_iter()
+                .map(move |entity| (name.clone(), entity))
+        })
+        .collect::<Vec<_>>();
+    for (name, entity) in &names {
+        pending.names.entry(name.clone()).or_default().push(*entity);
+    }
+    names
+}
+
+/// Records named entities collected by [`collect_references`] again, when the
+/// prototype which built them skips its build.
+pub(crate) fn record_references(world: &mut World, names: &[(String, Entity)]) {
+    if let Some(mut pending) = world.get_resource_mut::<PendingReferences>() {
+        for (name, entity) in names {
+            pending.names.entry(name.clone()).or_default().push(*entity);
+        }
+    }
+}
+
+/// Inserts a bundle which refers to other entities once the template currently
+/// being built is finished. If no template is being built, the references are
+/// resolved immediately.
+pub(crate) fn insert_resolved<B: Bundle>(
+    world: &mut World,
+    entity: Entity,
+    bundle: ResolveBundle<B>,
+) {
+    let pending = PendingBundle {
+        path: BuildPath::current(world),
+        names: bundle.names.clone(),
+        insert: Box::new(move |world, references| {
+            if let Ok(mut entity_mut) = world.get_entity_mut(entity) {
+                insert_changed(&mut entity_mut, bundle.resolve(references));
+            }
+        }),
+    };
+    match world.get_resource_mut::<PendingReferences>() {
+        Some(mut references) => references.bundles.push(pending),
+        None => resolve(world, PendingReferences::default(), vec![pending]),
+    }
+}
+
+/// Builds a template with reference resolution, by collecting the named
+/// entities while `build` runs and inserting the pending bundles afterwards.
+/// References never escape the template they are part of, even when templates
+/// are built while building another. Memos are part of the template they are
+/// in, so they don't call this.
+pub(crate) fn with_references(world: &mut World, build: impl FnOnce(&mut World)) {
+    let outer = world.remove_resource::<PendingReferences>();
+    world.init_resource::<PendingReferences>();
+    build(world);
+    let mut pending = world
+        .remove_resource::<PendingReferences>()
+        .unwrap_or_default();
+    let bundles = core::mem::take(&mut pending.bundles);
+    resolve(world, pending, bundles);
+    if let Some(outer) = outer {
+        world.insert_resource(outer);
+    }
+}
+
+/// Resolves the references of the pending bundles against the named entities,
+/// reporting names which are missing or ambiguous, and inserts the bundles.
+fn resolve(world: &mut World, pending: PendingReferences, bundles: Vec<PendingBundle>) {
+    let references = References {
+        names: pending
+            .names
+            .iter()
+            .map(|(name, entities)| (name.clone(), entities[0]))
+            .collect(),
+    };
+    for bundle in bundles {
+        for name in &bundle.names {
+            let error = match pending.names.get(name).map(Vec::len) {
+                None => TemplateBuildError::UnresolvedReference {
+                    path: bundle.path.clone(),
+                    name: name.clone(),
+                },
+                Some(1) => continue,
+                Some(_) => TemplateBuildError::AmbiguousReference {
+                    path: bundle.path.clone(),
+                    name: name.clone(),
+                },
+            };
+            report_error(world, error);
+        }
+        (bundle.insert)(world, &references);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Clone)]
+    struct ScrollArea;
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Scrollbar {
+        target: Entity,
+    }
+
+    #[test]
+    fn references_resolve_to_named_entities() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let template = template! {
+            { Node::default() } [
+                { Scrollbar { target: #scroll_area } };
+                scroll_area: { ScrollArea };
+            ];
+        };
+        template.clone().build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        let scrollbar = world.get::<Scrollbar>(children[0]).unwrap();
+        assert_eq!(scrollbar.target, children[1]);
+
+        // The target is re-used on rebuilds, so the reference doesn't change.
+        template.build(&mut world, root);
+        let scrollbar = world.get::<Scrollbar>(children[0]).unwrap();
+        assert_eq!(scrollbar.target, children[1]);
+    }
+
+    #[test]
+    fn references_follow_respawned_targets() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let template = |show: bool| {
+            template! {
+                { Scrollbar { target: #scroll_area } };
+                @if show {
+                    scroll_area: { ScrollArea };
+                } @else {
+                    scroll_area: { ScrollArea };
+                };
+            }
+        };
+        template(true).build(&mut world, root);
+        let shown = world.get::<Scrollbar>(root).unwrap().target;
+        assert!(world.get::<ScrollArea>(shown).is_some());
+
+        template(false).build(&mut world, root);
+        let hidden = world.get::<Scrollbar>(root).unwrap().target;
+        assert_ne!(hidden, shown);
+        assert!(world.get::<ScrollArea>(hidden).is_some());
+    }
+
+    #[test]
+    fn references_cross_into_and_out_of_memos() {
+        let mut world = World::new();
+        world.insert_resource(TemplateErrorMode::Panic);
+        let root = world.spawn_empty().id();
+
+        let template = |input: u32| {
+            template! {
+                { Node::default() } [
+                    { Scrollbar { target: #scroll_area } };
+                    @memo(input) {
+                        { Node::default() } [
+                            scroll_area: { ScrollArea };
+                            { Scrollbar { target: #other } };
+                        ];
+                    };
+                    other: { ScrollArea };
+                ];
+            }
+        };
+        let targets = |world: &World| {
+            let children = world.get::<Children>(root).unwrap().to_vec();
+            let inner = world.get::<Children>(children[1]).unwrap().to_vec();
+            let outer = world.get::<Scrollbar>(children[0]).unwrap().target;
+            let from_memo = world.get::<Scrollbar>(inner[1]).unwrap().target;
+            assert_eq!(outer, inner[0]);
+            assert_eq!(from_memo, children[2]);
+            outer
+        };
+
+        template(0).build(&mut world, root);
+        let scroll_area = targets(&world);
+
+        // While the memo skips its build, its names still resolve.
+        template(0).build(&mut world, root);
+        assert_eq!(targets(&world), scroll_area);
+        template(1).build(&mut world, root);
+        assert_eq!(targets(&world), scroll_area);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "reference `#missing` doesn't match any name in the template under `/`"
+    )]
+    fn unresolved_references_panic() {
+        let mut world = World::new();
+        world.insert_resource(TemplateErrorMode::Panic);
+        world.build(template! { { Scrollbar { target: #missing } }; });
+    }
+}
@@ -0,0 +1,174 @@
+use core::any::TypeId;
+
+use bevy_ecs::{
+    prelude::*,
+    relationship::{OrderedRelationshipSourceCollection, Relationship, RelationshipTarget},
+};
+
+use crate::Template;
+
+/// A template for the entities related to a [`Fragment`](crate::Fragment) by a
+/// relationship other than [`ChildOf`]. These are usually created with a
+/// `[Relationship => ...]` section in the [`template`](crate::template) macro.
+///
+/// Related entities are matched across builds by anchor and re-ordered in
+/// place, in the same way as children.
+#[derive(Clone)]
+pub struct RelatedTemplate {
+    kind: TypeId,
+    template: Template,
+    relate: fn(&mut EntityWorldMut, &[Entity]),
+    current: fn(&World, Entity) -> Vec<Entity>,
+}
+
+impl RelatedTemplate {
+    /// Creates a template for entities related to the fragment by `R`. The
+    /// relationship must be ordered, so that the related entities can be kept
+    /// in template order.
+    pub fn new<R: Relationship>(template: Template) -> Self
+    where
+        <R::RelationshipTarget as RelationshipTarget>::Collection:
+            OrderedRelationshipSourceCollection,
+    {
+        RelatedTemplate {
+            kind: TypeId::of::<R>(),
+            template,
+            relate: |entity_mut, related| {
+                entity_mut.insert_related::<R>(0, related);
+            },
+            current: |world, entity| {
+                world
+                    .get::<R::RelationshipTarget>(entity)
+                    .into_iter()
+                    .flat_map(|target| target.iter())
+                    .collect()
+            },
+        }
+    }
+
+    /// Returns the type of the relationship.
+    pub fn kind(&self) -> TypeId {
+        self.kind
+    }
+
+    /// Returns the template for the related entities.
+    pub fn template(&self) -> &Template {
+        &self.template
+    }
+
+    /// Adds prototypes to the end of the template.
+    pub(crate) fn extend(&mut self, template: Template) {
+        self.template.extend(template);
+    }
+
+    /// Takes the template for the related entities, leaving it empty.
+    pub(crate) fn take_template(&mut self) -> Template {
+        core::mem::take(&mut self.template)
+    }
+
+    /// Relates the entities to `entity_mut` in the given order, placing them
+    /// before any other related entities.
+    pub(crate) fn relate(&self, entity_mut: &mut EntityWorldMut, related: &[Entity]) {
+        (self.relate)(entity_mut, related);
+    }
+
+    /// Returns the entities currently related to `entity`, in order.
+    pub(crate) fn current(&self, world: &World, entity: Entity) -> Vec<Entity> {
+        (self.current)(world, entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Clone)]
+    struct Chest;
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Item(&'static str);
+
+    #[derive(Component)]
+    #[relationship(relationship_target = Contains)]
+    struct ContainedBy(Entity);
+
+    #[derive(Component)]
+    #[relationship_target(relationship = ContainedBy)]
+    struct Contains(Vec<Entity>);
+
+    fn contents(world: &World, chest: Entity) -> Vec<&'static str> {
+        world
+            .get::<Contains>(chest)
+            .unwrap()
+            .iter()
+            .map(|item| world.get::<Item>(item).unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn related_entities_are_reconciled_and_ordered() {
+        let mut world = World::new();
+        let chest = world.spawn_empty().id();
+
+        let inventory = |items: &[&'static str]| {
+            template! {
+                { Chest } [ContainedBy =>
+                    @for item in items.iter() => key(*item) { Item(item) };
+                ];
+            }
+        };
+
+        inventory(&["sword", "shield", "potion"]).build(&mut world, chest);
+        assert_eq!(contents(&world, chest), ["sword", "shield", "potion"]);
+        assert!(world.get::<Children>(chest).is_none());
+        let sword = world.get::<Contains>(chest).unwrap().iter().next().unwrap();
+
+        inventory(&["potion", "sword"]).build(&mut world, chest);
+        assert_eq!(contents(&world, chest), ["potion", "sword"]);
+        assert_eq!(
+            world.get::<Contains>(chest).unwrap().iter().nth(1),
+            Some(sword)
+        );
+        assert_eq!(world.query::<&Item>().iter(&world).count(), 2);
+    }
+
+    #[test]
+    fn removed_relationships_despawn_related_entities() {
+        let mut world = World::new();
+        let chest = world.spawn_empty().id();
+
+        template! {
+            { Chest } [ { Item("label") }; ] [ContainedBy => { Item("sword") }; ];
+        }
+        .build(&mut world, chest);
+        assert_eq!(world.query::<&Item>().iter(&world).count(), 2);
+
+        let template = template! { { Chest } [ { Item("label") }; ]; };
+        let diff = TemplateDiff::new(&world, chest, &template).unwrap();
+        assert_eq!(diff.despawned.len(), 1);
+
+        template.build(&mut world, chest);
+        assert_eq!(world.query::<&Item>().iter(&world).count(), 1);
+        assert!(world.get::<Contains>(chest).is_none());
+    }
+
+    #[test]
+    fn removed_fragments_despawn_their_related_entities() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! {
+            { Chest } [
+                { Chest } [ContainedBy => { Item("sword") }; { Item("shield") }; ];
+            ];
+        }
+        .build(&mut world, root);
+        assert_eq!(world.query::<&Item>().iter(&world).count(), 2);
+
+        template! { { Chest }; }.build(&mut world, root);
+        assert_eq!(world.query::<&Item>().iter(&world).count(), 0);
+        assert_eq!(world.query::<&Chest>().iter(&world).count(), 1);
+    }
+}
@@ -0,0 +1,178 @@
+use core::cell::UnsafeCell;
+
+use bevy_ecs::{
+    component::{ComponentId, ComponentInfo, StorageType, Tick},
+    prelude::*,
+    query::Access,
+    system::BoxedSystem,
+};
+
+use crate::{BuildTemplate, Template};
+
+/// A template which is rebuilt onto its entity whenever the inputs of the
+/// system producing it change. These are usually added with
+/// [`AppTemplateExt::add_template_root`](crate::AppTemplateExt::add_template_root),
+/// but can also be inserted on an existing entity.
+///
+/// The system is run by [`build_template_roots`] the first time, and again
+/// only when a resource it reads has changed, or a component it reads has
+/// changed, been added or been removed. Its output is built onto the entity
+/// like [`BuildTemplate::build`], so unchanged entities are re-used.
+///
+/// Inputs which aren't tracked by change detection, such as [`Local`]s,
+/// components only used in query filters like [`With`], or queries for
+/// [`Entity`] alone, don't cause the system to run again. Systems with access
+/// to every component or resource, such as those taking `&World`, are run
+/// every time.
+///
+/// Checking the inputs costs a walk over the change ticks of each component
+/// the system reads, stopping at the first change, plus a pass over the
+/// archetypes to count the entities with those components. This happens every
+/// frame, so its cost grows with the number of entities which have the
+/// components a root reads, but not with the rest of the world.
+#[derive(Component)]
+pub struct TemplateRoot {
+    system: Option<BoxedSystem<(), Template>>,
+    last_run: Option<Tick>,
+    counts: Vec<usize>,
+}
+
+impl TemplateRoot {
+    /// Creates a template root from a system returning a [`Template`].
+    pub fn new<M>(system: impl IntoSystem<(), Template, M>) -> Self {
+        TemplateRoot {
+            system: Some(Box::new(IntoSystem::into_system(system))),
+            last_run: None,
+            counts: Vec::new(),
+        }
+    }
+}
+
+/// Runs the system of each [`TemplateRoot`] whose inputs have changed since it
+/// last ran, and builds the resulting template onto the root entity. This is
+/// added to [`PostUpdate`](bevy_app::PostUpdate) by
+/// [`TemplatePlugin`](crate::TemplatePlugin).
+pub fn build_template_roots(world: &mut World) {
+    let roots = world
+        .query_filtered::<Entity, With<TemplateRoot>>()
+        .iter(world)
+        .collect::<Vec<_>>();
+    for root in roots {
+        // Take the system out of the root while it runs, so that the system
+        // and the template can access the world.
+        let mut root_mut = world.entity_mut(root);
+        let mut template_root = root_mut.get_mut::<TemplateRoot>().unwrap();
+        let Some(mut system) = template_root.system.take() else {
+            continue;
+        };
+        let last_run = template_root.last_run;
+        let previous_counts = core::mem::take(&mut template_root.counts);
+
+        if last_run.is_none() {
+            system.initialize(world);
+        }
+        let access = system.component_access();
+        let changed = last_run.is_none_or(|last_run| {
+            input_counts(world, access) != previous_counts
+                || inputs_changed(world, access, last_run)
+        });
+        if changed {
+            let template = system.run((), world);
+            template.build(world, root);
+        }
+
+        // Record the inputs as they are after the root was built, so that the
+        // changes made while building it aren't mistaken for changes to them.
+        let counts = input_counts(world, system.component_access());
+        let tick = world.increment_change_tick();
+        if let Ok(mut root_mut) = world.get_entity_mut(root) {
+            if let Some(mut template_root) = root_mut.get_mut::<TemplateRoot>() {
+                template_root.system = Some(system);
+                template_root.last_run = Some(tick);
+                template_root.counts = counts;
+            }
+        }
+    }
+}
+
+/// Returns the components read by a system, or `None` if it can read any
+/// component or resource and so has to be run every time.
+fn read_components(access: &Access<ComponentId>) -> Option<Vec<ComponentId>> {
+    if access.has_read_all_resources() {
+        return None;
+    }
+    let components = access.try_iter_component_access().ok()?;
+    Some(components.map(|kind| *kind.index()).collect())
+}
+
+/// Returns the number of entities with each component read by a system,
+/// followed by whether each resource it reads exists, so that removals can be
+/// detected. Systems which read everything get a count which never matches.
+fn input_counts(world: &World, access: &Access<ComponentId>) -> Vec<usize> {
+    let Some(components) = read_components(access) else {
+        return vec![usize::MAX];
+    };
+    let components = components.into_iter().map(|id| {
+        world
+            .archetypes()
+            .iter()
+            .filter(|archetype| archetype.contains(id))
+            .map(|archetype| archetype.len())
+            .sum()
+    });
+    let resources = access
+        .resource_reads_and_writes()
+        .map(|id| world.get_resource_change_ticks_by_id(id).is_some() as usize);
+    components.chain(resources).collect()
+}
+
+/// Returns true if any resource or component read by a system has been added
+/// or changed since `last_run`.
+fn inputs_changed(world: &World, access: &Access<ComponentId>, last_run: Tick) -> bool {
+    let Some(components) = read_components(access) else {
+        return true;
+    };
+    let this_run = world.read_change_tick();
+    let resource_changed = access.resource_reads_and_writes().any(|id| {
+        world
+            .get_resource_change_ticks_by_id(id)
+            .is_some_and(|ticks| ticks.is_changed(last_run, this_run))
+    });
+    resource_changed
+        || components
+            .into_iter()
+            .any(|id| component_changed(world, id, last_run, this_run))
+}
+
+/// Returns true if a component has been added or changed on any entity since
+/// `last_run`. Components stored in tables are checked a column at a time
+/// without looking up their entities, stopping at the first changed one.
+fn component_changed(world: &World, id: ComponentId, last_run: Tick, this_run: Tick) -> bool {
+    let is_changed = |tick: &UnsafeCell<Tick>| {
+        // SAFETY: the world is borrowed immutably, so the tick isn't being
+        // written to.
+        unsafe { *tick.get() }.is_newer_than(last_run, this_run)
+    };
+    let storages = world.storages();
+    let storage_type = world
+        .components()
+        .get_info(id)
+        .map(ComponentInfo::storage_type);
+    match storage_type {
+        Some(StorageType::Table) => storages.tables.iter().any(|table| {
+            table
+                .get_changed_ticks_slice_for(id)
+                .is_some_and(|ticks| ticks.iter().any(is_changed))
+        }),
+        Some(StorageType::SparseSet) => storages.sparse_sets.get(id).is_some_and(|set| {
+            world
+                .archetypes()
+                .iter()
+                .filter(|archetype| archetype.contains(id))
+                .flat_map(|archetype| archetype.entities())
+                .filter_map(|entity| set.get_changed_tick(entity.id()))
+                .any(is_changed)
+        }),
+        None => false,
+    }
+}
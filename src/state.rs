@@ -0,0 +1,431 @@
+use core::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    ops::Deref,
+};
+use std::{collections::HashMap, sync::Arc};
+
+use bevy_ecs::prelude::*;
+
+use crate::{
+    context::{nearest, with_context, Consumed, ContextValue},
+    template::{nested, nesting},
+    BuildTemplate, Key, Memo, Receipt, Template, Widget,
+};
+
+/// A type-erased state value.
+type StateValue = Arc<dyn Any + Send + Sync>;
+
+/// The local state of the widget built on an entity, by type. See [`state`].
+#[derive(Component, Default, Clone)]
+pub struct WidgetState {
+    values: HashMap<TypeId, StateValue>,
+}
+
+impl WidgetState {
+    /// Returns the state of type `T`, if the widget has used it.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+}
+
+/// Renders a widget again and builds it on an entity.
+type RerenderFn = Arc<dyn Fn(&mut World, Entity) + Send + Sync>;
+
+/// Renders the widget built on an entity again, so that it can be rebuilt when
+/// its state or the context values it read change.
+#[derive(Component)]
+pub(crate) struct Rerender(RerenderFn);
+
+/// The entity of the widget currently rendering on this thread, along with its
+/// state and the context values provided to it, and what it has initialized or
+/// read so far.
+pub(crate) struct RenderScope {
+    pub(crate) entity: Entity,
+    pub(crate) values: HashMap<TypeId, StateValue>,
+    pub(crate) initialized: Vec<(TypeId, StateValue)>,
+    pub(crate) contexts: HashMap<TypeId, ContextValue>,
+    pub(crate) consumed: HashMap<TypeId, Option<ContextValue>>,
+}
+
+thread_local! {
+    static SCOPE: RefCell<Option<RenderScope>> = const { RefCell::new(None) };
+
+    /// The entities widgets are currently being built on by this thread.
+    static BUILDING: RefCell<Vec<Entity>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Calls `f` with the scope of the widget which is currently rendering.
+///
+/// # Panics
+///
+/// Panics if no widget is rendering, naming the function which needed one.
+pub(crate) fn with_scope<R>(function: &str, f: impl FnOnce(&mut RenderScope) -> R) -> R {
+    SCOPE.with_borrow_mut(|scope| match scope.as_mut() {
+        Some(scope) => f(scope),
+        None => panic!("`{function}` can only be called while a widget is rendering"),
+    })
+}
+
+/// Removes the components a widget left on `entity`, unless a widget is being
+/// built on it. This is called whenever a fragment is built, so that once a
+/// widget is replaced by another kind of prototype, changes to its state or to
+/// the context values it read no longer render it again over its replacement.
+pub(crate) fn remove_widget(world: &mut World, entity: Entity) {
+    if BUILDING.with_borrow(|building| building.contains(&entity)) {
+        return;
+    }
+    if let Ok(mut entity_mut) = world.get_entity_mut(entity) {
+        if entity_mut.contains::<Rerender>() {
+            entity_mut.remove::<(Rerender, WidgetState, Consumed)>();
+        }
+    }
+}
+
+/// Renders the widget built on `entity` again and rebuilds it, if there is one.
+pub(crate) fn rerender(world: &mut World, entity: Entity) {
+    if let Some(rerender) = world.get::<Rerender>(entity) {
+        let rerender = rerender.0.clone();
+        rerender(world, entity);
+    }
+}
+
+/// A value of a widget's local state, along with the entity it is stored on.
+/// Created by calling [`state`] while the widget renders.
+///
+/// The value is a snapshot from when the widget rendered. Handlers which
+/// outlive the render, like observers, should use [`LocalState::update`] rather than
+/// [`LocalState::set`] so that they act on the current value.
+#[derive(Clone, Debug)]
+pub struct LocalState<T> {
+    entity: Entity,
+    value: T,
+}
+
+impl<T: Clone + Send + Sync + 'static> LocalState<T> {
+    /// Returns the entity the state is stored on.
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+
+    /// Returns the value of the state when the widget rendered.
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Replaces the value of the state, and rebuilds the widget.
+    pub fn set(&self, commands: &mut Commands, value: T) {
+        self.update(commands, move |state| *state = value);
+    }
+
+    /// Modifies the current value of the state, and rebuilds the widget.
+    pub fn update(&self, commands: &mut Commands, f: impl FnOnce(&mut T) + Send + 'static) {
+        let entity = self.entity;
+        let default = self.value.clone();
+        commands.queue(move |world: &mut World| {
+            let Ok(mut entity_mut) = world.get_entity_mut(entity) else {
+                return;
+            };
+            let mut widget_state = entity_mut.entry::<WidgetState>().or_default().into_mut();
+            let mut value = widget_state.get::<T>().cloned().unwrap_or(default);
+            f(&mut value);
+            widget_state
+                .values
+                .insert(TypeId::of::<T>(), Arc::new(value));
+            rerender(world, entity);
+        });
+    }
+}
+
+impl<T> Deref for LocalState<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// Returns the local state of type `T` of the widget which is currently
+/// rendering. The first time a widget uses a state, it starts out as `default`,
+/// and it keeps its value across rebuilds for as long as the widget is built on
+/// the same entity. Setting it rebuilds the widget.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// #[derive(Clone)]
+/// struct Collapsible {
+///     title: String,
+/// }
+///
+/// #[derive(Clone)]
+/// struct Open(bool);
+///
+/// impl Widget for Collapsible {
+///     fn render(&self) -> Template {
+///         let open = state(Open(false));
+///         let is_open = open.0;
+///         template! {
+///             { Button } on(move |_: Trigger<Pointer<Click>>, mut commands: Commands| {
+///                 open.update(&mut commands, |open| open.0 = !open.0);
+///             }) [
+///                 { Text::new(self.title.clone()) };
+///             ];
+///             @if is_open {
+///                 { Text::new("Contents") };
+///             };
+///         }
+///     }
+/// }
+/// ```
+///
+/// Each type is a separate piece of state, so wrap values in a newtype when a
+/// widget needs more than one of the same type.
+///
+/// # Panics
+///
+/// Panics if no widget is rendering, for example when [`Widget::render`] is
+/// called directly.
+pub fn state<T: Clone + Send + Sync + 'static>(default: T) -> LocalState<T> {
+    with_scope("state", |scope| {
+        let kind = TypeId::of::<T>();
+        let value = match scope
+            .values
+            .get(&kind)
+            .and_then(|value| value.downcast_ref())
+        {
+            Some(value) => T::clone(value),
+            None => {
+                let value = Arc::new(default.clone());
+                scope.values.insert(kind, value.clone());
+                scope.initialized.push((kind, value));
+                default
+            }
+        };
+        LocalState {
+            entity: scope.entity,
+            value,
+        }
+    })
+}
+
+/// Renders a widget with the state stored on `entity` and the context values
+/// provided to it, returning the template along with the scope it rendered in.
+pub(crate) fn render<W: Widget>(
+    world: &World,
+    entity: Option<Entity>,
+    widget: &W,
+) -> (Template, RenderScope) {
+    let values = entity
+        .and_then(|entity| world.get::<WidgetState>(entity))
+        .map(|state| state.values.clone())
+        .unwrap_or_default();
+    let scope = RenderScope {
+        entity: entity.unwrap_or(Entity::PLACEHOLDER),
+        values,
+        initialized: Vec::new(),
+        contexts: nearest(world, entity),
+        consumed: HashMap::new(),
+    };
+    let outer = SCOPE.replace(Some(scope));
+    let template = widget.render();
+    let scope = SCOPE.replace(outer).unwrap();
+    (template, scope)
+}
+
+/// Renders a widget and builds it on `entity`, storing the state it initialized
+/// and the context values it read, along with how to render it again when they
+/// change.
+pub(crate) fn build_widget<W: Widget>(world: &mut World, entity: Entity, widget: W) {
+    let depth = nesting(entity);
+    with_context(world, entity, |world| {
+        let (template, scope) = render(world, Some(entity), &widget);
+        let mut entity_mut = world.entity_mut(entity);
+        if !scope.initialized.is_empty() {
+            entity_mut
+                .entry::<WidgetState>()
+                .or_default()
+                .into_mut()
+                .values
+                .extend(scope.initialized);
+        }
+        if scope.consumed.is_empty() {
+            entity_mut.remove::<Consumed>();
+        } else {
+            entity_mut.insert(Consumed(scope.consumed));
+        }
+        entity_mut.insert(Rerender(Arc::new(move |world, entity| {
+            nested(entity, depth, || {
+                build_widget(world, entity, widget.clone())
+            })
+        })));
+        // Clear the input of a memo which was built on the entity before,
+        // unless the widget is rendered as a memo itself.
+        if template
+            .first()
+            .is_none_or(|root| root.kind() != Key::new(TypeId::of::<Memo>()))
+        {
+            if let Some(mut receipt) = entity_mut.get_mut::<Receipt>() {
+                receipt.memo = None;
+            }
+        }
+        BUILDING.with_borrow_mut(|building| building.push(entity));
+        template.build(world, entity);
+        BUILDING.with_borrow_mut(|building| building.pop());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Label(String);
+
+    #[derive(Event)]
+    struct Increment;
+
+    #[derive(Clone)]
+    struct Counter {
+        label: &'static str,
+    }
+
+    #[derive(Clone)]
+    struct Count(u32);
+
+    impl Widget for Counter {
+        fn render(&self) -> Template {
+            let count = state(Count(0));
+            template! {
+                { Label(format!("{} {}", self.label, count.0)) } on(
+                    move |_: Trigger<Increment>, mut commands: Commands| {
+                        count.update(&mut commands, |count| count.0 += 1);
+                    }
+                );
+            }
+        }
+    }
+
+    fn counter(label: &'static str) -> Template {
+        template! {
+            { Node::default() } [
+                @widget { Counter { label } };
+            ];
+        }
+    }
+
+    #[test]
+    fn state_persists_and_rebuilds_the_widget() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        counter("clicks").build(&mut world, root);
+        let widget = world.get::<Children>(root).unwrap()[0];
+        assert_eq!(world.get::<Label>(widget), Some(&Label("clicks 0".into())));
+
+        world.trigger_targets(Increment, widget);
+        world.trigger_targets(Increment, widget);
+        world.flush();
+        assert_eq!(world.get::<Label>(widget), Some(&Label("clicks 2".into())));
+        assert_eq!(
+            world
+                .get::<WidgetState>(widget)
+                .unwrap()
+                .get::<Count>()
+                .unwrap()
+                .0,
+            2
+        );
+
+        // Rebuilding the parent keeps the state, since the widget is re-used.
+        counter("taps").build(&mut world, root);
+        assert_eq!(world.get::<Children>(root).unwrap()[0], widget);
+        assert_eq!(world.get::<Label>(widget), Some(&Label("taps 2".into())));
+    }
+
+    #[test]
+    fn replaced_widgets_leave_no_state_behind() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! { { Node::default() } [ x: @widget { Counter { label: "clicks" } }; ]; }
+            .build(&mut world, root);
+        let x = world.get::<Children>(root).unwrap()[0];
+        let count = state_of(&world, x);
+
+        // A fragment under the same name re-uses the entity, but not the widget.
+        template! { { Node::default() } [ x: { Label("plain".into()) }; ]; }
+            .build(&mut world, root);
+        assert_eq!(world.get::<Children>(root).unwrap()[0], x);
+        assert!(world.get::<WidgetState>(x).is_none());
+
+        // Updating the widget's old state doesn't render it over the fragment.
+        count.set(&mut world.commands(), Count(5));
+        world.flush();
+        assert_eq!(world.get::<Label>(x), Some(&Label("plain".into())));
+    }
+
+    #[derive(Clone)]
+    struct Pair;
+
+    impl Widget for Pair {
+        fn render(&self) -> Template {
+            let count = state(Count(0));
+            template! {
+                { Label(format!("first {}", count.0)) };
+                { Label(format!("second {}", count.0)) };
+            }
+        }
+    }
+
+    #[test]
+    fn widgets_with_several_roots_keep_them_when_they_come_first() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+        let page = || template! { @widget { Pair }; { Label("last".into()) }; };
+
+        page().build(&mut world, root);
+        let entities = world.entities().len();
+        let roots = world.get::<Receipt>(root).unwrap().roots().count();
+        assert_eq!(roots, 2);
+
+        // Rebuilding the template and rendering the widget again on its own
+        // both re-use the roots of the widget and of the template.
+        page().build(&mut world, root);
+        state_of(&world, root).set(&mut world.commands(), Count(1));
+        world.flush();
+        assert_eq!(labels(&mut world), ["first 1", "last", "second 1"]);
+        page().build(&mut world, root);
+        assert_eq!(world.entities().len(), entities);
+        assert_eq!(world.get::<Receipt>(root).unwrap().roots().count(), roots);
+        assert_eq!(labels(&mut world), ["first 1", "last", "second 1"]);
+    }
+
+    /// Returns the text of every label in the world, sorted.
+    fn labels(world: &mut World) -> Vec<String> {
+        let mut labels = world
+            .query::<&Label>()
+            .iter(world)
+            .map(|label| label.0.clone())
+            .collect::<Vec<_>>();
+        labels.sort();
+        labels
+    }
+
+    /// Returns the `Count` state of the counter built on `entity`.
+    fn state_of(world: &World, entity: Entity) -> LocalState<Count> {
+        let value = world.get::<WidgetState>(entity).unwrap().get::<Count>();
+        LocalState {
+            entity,
+            value: value.unwrap().clone(),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "`state` can only be called while a widget is rendering")]
+    fn state_outside_render_panics() {
+        Counter { label: "clicks" }.render();
+    }
+}
@@ -1,9 +1,33 @@
-use std::collections::HashSet;
-use bevy_ecs::prelude::*;
+use core::{
+    any::{Any, TypeId},
+    hash::{Hash, Hasher},
+};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    sync::Arc,
+};
+
+use bevy_ecs::{
+    component::ComponentId,
+    prelude::*,
+    relationship::{OrderedRelationshipSourceCollection, Relationship, RelationshipTarget},
+    system::IntoObserverSystem,
+};
+
+use crate::{
+    compare::insert_changed,
+    context::{provide, provide_value, with_context},
+    observer::{build_observers, diff_observers},
+    reference::{insert_resolved, record_reference, with_references},
+    state::remove_widget,
+    ContextProvider, EntityDiff, FragmentObserver, PendingBuilds, RelatedTemplate, ResolveBundle,
+    SkipUnchanged, TemplateDiff,
+};
 
 /// A template is an ordered collection of heterogenous prototypes, which can be
 /// inserted into the world. Returned by the [`template`] macro.
-/// 
+///
 /// Because the underlying items are cloneable, you can now clone your templates.
 pub type Template = Vec<Box<dyn Prototype + Send + Sync + 'static>>;
 
@@ -28,12 +52,28 @@ pub trait Prototype: PrototypeClone {
     /// Returns the name of this prototype.
     fn name(&self) -> Option<String>;
 
+    /// Returns the anchor of this prototype, which identifies it among its
+    /// siblings. Defaults to the name of the prototype.
+    fn anchor(&self) -> Option<Anchor> {
+        self.name().map(Anchor::Named)
+    }
+
+    /// Returns a stable identity for the kind of this prototype. For a
+    /// [`Fragment`] this is the type of its bundle, usually as a
+    /// `Key::new(TypeId::of::<B>())`, but prototypes which are only known at
+    /// runtime can compute it from their contents.
+    ///
+    /// Unnamed prototypes are matched to entities by position, so when the kind
+    /// of prototype at a position changes between builds, the old entity is
+    /// despawned and a fresh one is spawned instead of merging the two.
+    fn kind(&self) -> Key;
+
     /// Builds the prototype on a specific entity.
-    /// 
-    /// The prototype uses a receipt to keep track of the state it left the
-    /// world in when it was last built. The first time it is built, it should
-    /// use the default receipt. The next time it is built, you should pass the
-    /// same receipt back in.
+    ///
+    /// The prototype uses a [`Receipt`] to keep track of the state it left the
+    /// world in when it was last built. The receipt is stored as a component on
+    /// the entity, so building onto the same entity again picks it back up. The
+    /// first time an entity is built on, the default receipt is used.
     ///
     /// The receipt is used to clean up old values after which were previously
     /// included in the template and now are not. Components added by the
@@ -46,7 +86,437 @@ pub trait Prototype: PrototypeClone {
     ///
     /// To instead build an entire `Template` at the root level, see
     /// [`BuildTemplate::build`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `entity` does not exist. See [`Prototype::try_build`] for a
+    /// fallible version.
     fn build(self: Box<Self>, world: &mut World, entity: Entity);
+
+    /// Builds the prototype on a specific entity, returning an error instead of
+    /// panicking if the entity does not exist. See [`Prototype::build`] for more
+    /// documentation.
+    fn try_build(
+        self: Box<Self>,
+        world: &mut World,
+        entity: Entity,
+    ) -> Result<(), TemplateBuildError> {
+        if world.get_entity(entity).is_err() {
+            return Err(TemplateBuildError::EntityNotFound { entity });
+        }
+        self.build(world, entity);
+        Ok(())
+    }
+
+    /// Records the changes building this prototype on `entity` would make in
+    /// `diff`, without modifying the world. `entity` is `None` if the prototype
+    /// would be built on a freshly spawned entity.
+    ///
+    /// See [`TemplateDiff`] for more documentation.
+    fn diff(&self, world: &World, entity: Option<Entity>, diff: &mut TemplateDiff);
+}
+
+/// An anchor identifies a prototype among its siblings, so that it can be
+/// matched to the same entity each time a template is built.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Anchor {
+    /// An unnamed prototype, identified by its position among its unnamed
+    /// siblings.
+    Auto(usize),
+    /// A statically named prototype, identified by its name.
+    Named(String),
+    /// A dynamically named prototype, identified by the name computed for it
+    /// when the template was created.
+    Dynamic(String),
+    /// A keyed prototype, identified by its key. Elements of a `@for` list are
+    /// keyed within the scope of their list.
+    Keyed(Key),
+    /// A prototype from a scoped template, such as a branch of an `@if`,
+    /// identified by the key of the scope, its occurrence among the siblings,
+    /// and its anchor within the scope. See [`scoped`].
+    ///
+    /// The occurrence is 0 unless the same scope is spliced in more than once
+    /// among the same siblings, such as when a helper function returning a
+    /// template is spliced twice. Repeated anchors are then numbered in order
+    /// when the siblings are matched.
+    Scoped(Key, usize, Box<Anchor>),
+}
+
+impl Anchor {
+    /// Returns true if the anchor identifies an unnamed prototype by its
+    /// position, either among its siblings or within a scope.
+    pub fn is_positional(&self) -> bool {
+        match self {
+            Anchor::Auto(_) => true,
+            Anchor::Scoped(_, _, anchor) => anchor.is_positional(),
+            _ => false,
+        }
+    }
+}
+
+impl core::fmt::Display for Anchor {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Anchor::Auto(index) => write!(f, "#{index}"),
+            Anchor::Named(name) | Anchor::Dynamic(name) => write!(f, "{name}"),
+            Anchor::Keyed(key) => write!(f, "{key}"),
+            Anchor::Scoped(scope, occurrence, anchor) => {
+                match scope.downcast_ref::<Site>() {
+                    Some(site) => write!(f, "{site}")?,
+                    None => write!(f, "{scope}")?,
+                }
+                if *occurrence > 0 {
+                    write!(f, "({occurrence})")?;
+                }
+                write!(f, ":{anchor}")
+            }
+        }
+    }
+}
+
+/// The place in a [`template`] which created a scope, such as a branch of an
+/// `@if`, an arm of a `@match` or a `@for` list. This is the key of the
+/// [`Anchor::Scoped`] anchors created by the macro.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Site {
+    /// The type of a unit struct declared where the scope is created, which is
+    /// unique to that place in the source.
+    pub id: TypeId,
+    /// The kind of scope, such as `"@if"`, used when displaying anchors.
+    pub kind: &'static str,
+    /// The branch or arm of the scope.
+    pub index: usize,
+}
+
+impl core::fmt::Display for Site {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}[{}]", self.kind, self.index)
+    }
+}
+
+/// A type-erased key, which identifies a prototype among its siblings. Any
+/// value which is `Hash + Eq` can be used as a key, and keys of different types
+/// are never equal.
+///
+/// The hash of the value is computed once when the key is created, so keys are
+/// cheap to compare and look up.
+#[derive(Clone)]
+pub struct Key {
+    hash: u64,
+    value: Arc<dyn DynKey>,
+}
+
+impl Key {
+    /// Creates a key from a value.
+    pub fn new<T: Hash + Eq + Send + Sync + 'static>(value: T) -> Self {
+        let mut hasher = DefaultHasher::new();
+        TypeId::of::<T>().hash(&mut hasher);
+        value.hash(&mut hasher);
+        Key {
+            hash: hasher.finish(),
+            value: Arc::new(value),
+        }
+    }
+
+    /// Returns the value of the key, if it is of type `T`.
+    pub fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        self.value.as_any().downcast_ref()
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.value.dyn_eq(other.value.as_any())
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl core::fmt::Debug for Key {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Key({self})")
+    }
+}
+
+impl core::fmt::Display for Key {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}@{:016x}", self.value.type_name(), self.hash)
+    }
+}
+
+/// Object-safe equality for the values stored in a [`Key`].
+trait DynKey: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn dyn_eq(&self, other: &dyn Any) -> bool;
+    fn type_name(&self) -> &'static str;
+}
+
+impl<T: Eq + Send + Sync + 'static> DynKey for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn dyn_eq(&self, other: &dyn Any) -> bool {
+        other.downcast_ref::<T>() == Some(self)
+    }
+
+    fn type_name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// An error encountered while building a template.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TemplateBuildError {
+    /// The entity the template was to be built on does not exist. This usually
+    /// means it was despawned before a queued build was applied.
+    EntityNotFound {
+        /// The missing entity.
+        entity: Entity,
+    },
+    /// Two siblings share the same anchor. The second sibling is treated as if
+    /// it were unnamed.
+    DuplicateAnchor {
+        /// The anchors leading from the root to the parent of the siblings.
+        path: Vec<Anchor>,
+        /// The anchor shared by the siblings.
+        anchor: Anchor,
+    },
+    /// An unnamed prototype has the same kind as a dynamically named or keyed
+    /// sibling, which can mean a name was forgotten when generating a list.
+    /// Since this is only a heuristic, and unnamed headers or footers of lists
+    /// are common, it is always logged as a warning rather than reported
+    /// according to the [`TemplateErrorMode`].
+    MissingKey {
+        /// The anchors leading from the root to the parent of the siblings.
+        path: Vec<Anchor>,
+        /// The position of the prototype among its unnamed siblings.
+        anchor: Anchor,
+    },
+    /// A bundle refers to a name which no prototype in the template has. The
+    /// reference resolves to [`Entity::PLACEHOLDER`].
+    UnresolvedReference {
+        /// The anchors leading from the root to the prototype with the bundle.
+        path: Vec<Anchor>,
+        /// The name which was referred to.
+        name: String,
+    },
+    /// A bundle refers to a name which more than one prototype in the template
+    /// has. The reference resolves to the first of them to be built.
+    AmbiguousReference {
+        /// The anchors leading from the root to the prototype with the bundle.
+        path: Vec<Anchor>,
+        /// The name which was referred to.
+        name: String,
+    },
+    /// A splice in a [`DynamicTemplate`](crate::DynamicTemplate) refers to a
+    /// name which no template in the [`TemplateLibrary`](crate::TemplateLibrary)
+    /// has. Nothing is built for the splice.
+    UnknownTemplate {
+        /// The anchors leading from the root to the splice.
+        path: Vec<Anchor>,
+        /// The name which was referred to.
+        name: String,
+    },
+    /// A reflected component isn't registered as a component in the world's
+    /// `AppTypeRegistry`. The component is not inserted.
+    UnregisteredComponent {
+        /// The anchors leading from the root to the fragment with the component.
+        path: Vec<Anchor>,
+        /// The type path of the component.
+        type_path: String,
+    },
+}
+
+impl core::fmt::Display for TemplateBuildError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let (path, message) = match self {
+            TemplateBuildError::EntityNotFound { entity } => {
+                return write!(f, "cannot build a template on {entity}, it does not exist");
+            }
+            TemplateBuildError::DuplicateAnchor { path, anchor } => {
+                (path, format!("duplicate anchor `{anchor}`"))
+            }
+            TemplateBuildError::MissingKey { path, anchor } => (
+                path,
+                format!(
+                    "unnamed prototype `{anchor}` has dynamically named siblings of the same kind"
+                ),
+            ),
+            TemplateBuildError::UnresolvedReference { path, name } => (
+                path,
+                format!("reference `#{name}` doesn't match any name in the template"),
+            ),
+            TemplateBuildError::AmbiguousReference { path, name } => (
+                path,
+                format!("reference `#{name}` matches more than one name in the template"),
+            ),
+            TemplateBuildError::UnknownTemplate { path, name } => (
+                path,
+                format!("splice `@{name}` doesn't match any template in the library"),
+            ),
+            TemplateBuildError::UnregisteredComponent { path, type_path } => (
+                path,
+                format!("`{type_path}` isn't registered as a reflected component"),
+            ),
+        };
+        write!(f, "{message} under `/")?;
+        for (index, anchor) in path.iter().enumerate() {
+            if index > 0 {
+                write!(f, "/")?;
+            }
+            write!(f, "{anchor}")?;
+        }
+        write!(f, "`")
+    }
+}
+
+impl core::error::Error for TemplateBuildError {}
+
+/// Controls how [`TemplateBuildError`]s which don't prevent a template from
+/// being built are reported. Errors which do, such as
+/// [`TemplateBuildError::EntityNotFound`], are returned from the `try_build`
+/// methods instead. Insert this as a resource to override the default,
+/// which is to panic in debug builds and log an error in release builds.
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TemplateErrorMode {
+    /// Panic when an error is encountered.
+    Panic,
+    /// Log the error and continue building.
+    Log,
+}
+
+impl Default for TemplateErrorMode {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            TemplateErrorMode::Panic
+        } else {
+            TemplateErrorMode::Log
+        }
+    }
+}
+
+/// Reports an error according to the [`TemplateErrorMode`] of the world.
+/// [`TemplateBuildError::MissingKey`] is only logged as a warning.
+pub(crate) fn report_error(world: &World, error: TemplateBuildError) {
+    if let TemplateBuildError::MissingKey { .. } = error {
+        log::warn!("{error}");
+        return;
+    }
+    match world
+        .get_resource::<TemplateErrorMode>()
+        .copied()
+        .unwrap_or_default()
+    {
+        TemplateErrorMode::Panic => panic!("{error}"),
+        TemplateErrorMode::Log => log::error!("{error}"),
+    }
+}
+
+/// The anchors leading from the root of the template currently being built to
+/// the entity currently being built.
+#[derive(Resource, Default)]
+pub(crate) struct BuildPath(Vec<Anchor>);
+
+impl BuildPath {
+    /// Returns the path to the entity currently being built.
+    pub(crate) fn current(world: &World) -> Vec<Anchor> {
+        world
+            .get_resource::<BuildPath>()
+            .map(|path| path.0.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// A receipt records the state a [`Prototype`] left an entity in when it was
+/// last built. It is stored as a component on every entity a template is built
+/// on, and is read back the next time a prototype is built on that entity.
+///
+/// See [`Prototype::build`] for more information about how receipts are used.
+#[derive(Component, Default, Clone, Debug)]
+pub struct Receipt {
+    /// The components inserted by the last build.
+    pub(crate) components: HashSet<ComponentId>,
+    /// The children built by the last build, by anchor.
+    pub(crate) anchors: HashMap<Anchor, Anchored>,
+    /// The additional root entities built by the last [`BuildTemplate::build`]
+    /// onto this entity, by anchor. Memos and widgets which come first build
+    /// their own template onto the same entity, so the roots of each level of
+    /// nesting are kept separately.
+    pub(crate) roots: Vec<HashMap<Anchor, Anchored>>,
+    /// The observers watching this entity spawned by the last build, along with
+    /// their keys and the kind of their system.
+    pub(crate) observers: Vec<(Option<Key>, Anchored)>,
+    /// The entities related to this entity by relationships other than
+    /// [`ChildOf`] built by the last build, by relationship and anchor.
+    pub(crate) related: HashMap<TypeId, HashMap<Anchor, Anchored>>,
+    /// The input of the [`Memo`](crate::Memo) last built on this entity.
+    pub(crate) memo: Option<Key>,
+    /// The named entities built by the memo, which references resolve to while
+    /// it skips its build.
+    pub(crate) memo_references: Vec<(String, Entity)>,
+}
+
+/// An entity built for an anchor, along with the kind of prototype which was
+/// built on it. Also used for observer entities, along with the kind of their
+/// system.
+#[derive(Clone, Debug)]
+pub(crate) struct Anchored {
+    pub(crate) entity: Entity,
+    pub(crate) kind: Key,
+}
+
+impl Receipt {
+    /// Returns the components inserted by the last build.
+    pub fn components(&self) -> impl Iterator<Item = ComponentId> + '_ {
+        self.components.iter().copied()
+    }
+
+    /// Returns the child built for the given anchor by the last build.
+    pub fn anchor(&self, anchor: &Anchor) -> Option<Entity> {
+        self.anchors.get(anchor).map(|anchored| anchored.entity)
+    }
+
+    /// Returns the anchors of the children built by the last build, along
+    /// with the entities they were built on.
+    pub fn anchors(&self) -> impl Iterator<Item = (&Anchor, Entity)> + '_ {
+        self.anchors
+            .iter()
+            .map(|(anchor, anchored)| (anchor, anchored.entity))
+    }
+
+    /// Returns the anchors of the additional root entities built by the last
+    /// [`BuildTemplate::build`] onto this entity, along with their entities.
+    /// This includes the roots of templates built by a memo or widget which
+    /// comes first.
+    pub fn roots(&self) -> impl Iterator<Item = (&Anchor, Entity)> + '_ {
+        self.roots
+            .iter()
+            .flatten()
+            .map(|(anchor, anchored)| (anchor, anchored.entity))
+    }
+
+    /// Returns the anchors of the entities related to this entity by `R` which
+    /// were built by the last build, along with their entities.
+    pub fn related<R: Relationship>(&self) -> impl Iterator<Item = (&Anchor, Entity)> + '_ {
+        self.related
+            .get(&TypeId::of::<R>())
+            .into_iter()
+            .flatten()
+            .map(|(anchor, anchored)| (anchor, anchored.entity))
+    }
+
+    /// Returns the observer entities watching this entity which were spawned by
+    /// the last build, in template order.
+    pub fn observers(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.observers.iter().map(|(_, anchored)| anchored.entity)
+    }
 }
 
 /// Implement `Clone` for our boxed trait object.
@@ -60,27 +530,164 @@ pub trait BuildTemplate {
     /// Builds a template onto the world.
     ///
     /// Each top-level prototype in the template will be built on a different
-    /// entity. Each prototype's name is used to determine what entity to build
+    /// entity. The first prototype is always built on `entity` itself. Every
+    /// other prototype is built on its own entity, which is recorded in the
+    /// [`Receipt`] of `entity` and re-used the next time a template is built
+    /// onto it. Each prototype's name is used to determine what entity to build
     /// it on, so naming root level entities is recomended. Unamed prototypes
-    /// are indexed according to order. Different templates *will* conflict if
-    /// they share the same root names or if root names are ommited on both.
+    /// are indexed according to order. Root entities which are no longer part
+    /// of the template are despawned recursively.
+    ///
+    /// Since `entity` can't move, the first root is positional even when it is
+    /// named. If the roots are reordered so that a different prototype comes
+    /// first, `entity` is rebuilt from that prototype, the entity it was built
+    /// on before is despawned, and the prototype which used to come first is
+    /// built on a new entity. Keep the first root in place if its entities
+    /// should be re-used.
+    ///
+    /// When the first root is a memo or widget, its own template is built onto
+    /// `entity` as well. Its additional roots are recorded separately from the
+    /// ones of the outer template, so both are re-used.
     ///
     /// For information about what happens when a prototype is built on a
     /// specific entity, see [`Prototype::build`].
+    ///
+    /// Bundles which refer to other entities in the template by name (see
+    /// [`Fragment::resolve`]) are inserted after every prototype has been built,
+    /// once the entities they refer to are known.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `entity` does not exist. See [`BuildTemplate::try_build`] for
+    /// a fallible version.
     fn build(self, world: &mut World, entity: Entity);
+
+    /// Builds a template onto the world, returning an error instead of
+    /// panicking if `entity` does not exist. See [`BuildTemplate::build`] for
+    /// more documentation.
+    fn try_build(self, world: &mut World, entity: Entity) -> Result<(), TemplateBuildError>;
 }
 
 impl BuildTemplate for Template {
     fn build(self, world: &mut World, entity: Entity) {
-        for prototype in self.into_iter() {
-            prototype.build(world, entity);
+        // Bundles which refer to other entities are inserted once everything
+        // has been built.
+        with_context(world, entity, |world| {
+            with_references(world, |world| build_roots(world, entity, self));
+        });
+    }
+
+    fn try_build(self, world: &mut World, entity: Entity) -> Result<(), TemplateBuildError> {
+        if world.get_entity(entity).is_err() {
+            return Err(TemplateBuildError::EntityNotFound { entity });
         }
+        self.build(world, entity);
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// The entities templates are currently being built onto by this thread,
+    /// innermost last, along with whether a template has been built or kept
+    /// onto the same entity one level deeper.
+    static ROOTS: RefCell<Vec<(Entity, bool)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Builds a template onto `entity` as described in [`BuildTemplate::build`],
+/// without resolving references.
+pub(crate) fn build_roots(world: &mut World, entity: Entity, template: Template) {
+    // A template built onto an entity while another is being built onto it,
+    // by a memo or widget which comes first, records its roots one level
+    // deeper, so that neither replaces the other's.
+    let depth = nesting(entity);
+    keep_nested_roots(entity);
+    ROOTS.with_borrow_mut(|frames| frames.push((entity, false)));
+
+    // Take the additional roots left behind by the previous build.
+    let previous_roots = world
+        .get_mut::<Receipt>(entity)
+        .and_then(|mut receipt| receipt.roots.get_mut(depth).map(core::mem::take))
+        .unwrap_or_default();
+
+    // Build the first prototype on the entity itself, and the rest on their
+    // own entities.
+    let mut prototypes = template.into_iter();
+    if let Some(prototype) = prototypes.next() {
+        if let Some(anchor) = prototype.anchor() {
+            record_reference(world, &anchor, entity);
+        }
+        prototype.build(world, entity);
+    }
+    let (_, roots) = build_children(world, prototypes.collect(), previous_roots);
+    let nested = ROOTS
+        .with_borrow_mut(|frames| frames.pop())
+        .is_some_and(|(_, nested)| nested);
+
+    // Record the additional roots so they can be re-used next time, and
+    // despawn the deeper ones if the first prototype no longer builds them.
+    let mut entity_mut = world.entity_mut(entity);
+    let mut receipt = entity_mut.entry::<Receipt>().or_default().into_mut();
+    if receipt.roots.len() <= depth {
+        receipt.roots.resize_with(depth + 1, HashMap::new);
     }
+    receipt.roots[depth] = roots;
+    let stale = if nested {
+        Vec::new()
+    } else {
+        receipt.roots.split_off(depth + 1)
+    };
+    for anchored in stale.into_iter().flat_map(HashMap::into_values) {
+        despawn_built(world, anchored.entity);
+    }
+}
+
+/// Keeps the roots recorded one level deeper than the template currently being
+/// built onto `entity`, when a memo which comes first skips its build.
+pub(crate) fn keep_nested_roots(entity: Entity) {
+    ROOTS.with_borrow_mut(|frames| {
+        if let Some(outer) = frames.iter_mut().rev().find(|(built, _)| *built == entity) {
+            outer.1 = true;
+        }
+    });
+}
+
+/// Returns how many templates are currently being built onto `entity`.
+pub(crate) fn nesting(entity: Entity) -> usize {
+    ROOTS.with_borrow(|frames| frames.iter().filter(|(built, _)| *built == entity).count())
+}
+
+/// Calls `build` as if `depth` templates were being built onto `entity`, so
+/// that a widget which is rebuilt on its own records its roots at the same
+/// level as when it was built as part of a template.
+pub(crate) fn nested<R>(entity: Entity, depth: usize, build: impl FnOnce() -> R) -> R {
+    let missing = depth.saturating_sub(nesting(entity));
+    ROOTS.with_borrow_mut(|frames| frames.extend((0..missing).map(|_| (entity, true))));
+    let result = build();
+    ROOTS.with_borrow_mut(|frames| frames.truncate(frames.len() - missing));
+    result
 }
 
 pub trait WorldTemplateExt {
     /// Builds a template. See [`BuildTemplate::build`] for more documentation.
     fn build(&mut self, template: Template);
+
+    /// Opts the component `T` in to being compared with its live value when a
+    /// template is built, so that it is only written (and marked as changed)
+    /// when the value differs. See [`SkipUnchanged`] for more documentation.
+    fn skip_unchanged<T: Component + PartialEq>(&mut self) -> &mut Self;
+
+    /// Replaces a context value provided by `entity` to the entities below it,
+    /// and rebuilds only the widgets which read it. The value is replaced again
+    /// the next time a template is built on `entity`. See [`ContextProvider`].
+    fn provide<T: Clone + PartialEq + Send + Sync + 'static>(&mut self, entity: Entity, value: T);
+
+    /// Creates a [`DynamicTemplate`](crate::DynamicTemplate) from the live
+    /// hierarchy below `root`, which can be written out with
+    /// [`DynamicTemplate::to_text`](crate::DynamicTemplate::to_text). See
+    /// [`DynamicTemplate::extract`](crate::DynamicTemplate::extract) for more
+    /// documentation.
+    #[cfg(feature = "reflect")]
+    fn extract_template(&self, root: Entity) -> crate::DynamicTemplate;
 }
 
 impl WorldTemplateExt for World {
@@ -88,94 +695,649 @@ impl WorldTemplateExt for World {
         let entity_id = self.spawn_empty().id();
         template.build(self, entity_id);
     }
+
+    fn skip_unchanged<T: Component + PartialEq>(&mut self) -> &mut Self {
+        let id = self.register_component::<T>();
+        self.get_resource_or_init::<SkipUnchanged>()
+            .register::<T>(id);
+        self
+    }
+
+    fn provide<T: Clone + PartialEq + Send + Sync + 'static>(&mut self, entity: Entity, value: T) {
+        provide_value(self, entity, ContextProvider::new(value));
+    }
+
+    #[cfg(feature = "reflect")]
+    fn extract_template(&self, root: Entity) -> crate::DynamicTemplate {
+        crate::DynamicTemplate::extract(self, root)
+    }
 }
 
 /// A command for building a template. The shorthand for this is
-/// [`CommandsTemplateExt::build`]. See [`BuildTemplate::build`] for more
-/// documentation.
+/// [`CommandsTemplateExt::build`] or [`EntityCommandsTemplateExt::build_to`]. See
+/// [`BuildTemplate::build`] for more documentation.
+///
+/// When the [`TemplatePlugin`](crate::TemplatePlugin) is added, the template is
+/// queued in [`PendingBuilds`] rather than built when the command is applied.
+///
+/// The command returns a [`TemplateBuildError`] if the entity no longer exists
+/// when it is applied, which is handled by the default error handler. Queue it
+/// with [`Commands::queue_handled`] to choose how the error is handled instead.
+/// Queued templates whose entity is despawned before they are built are
+/// skipped without an error.
 pub struct BuildTemplateCommand(Template, Entity);
 
-impl Command for BuildTemplateCommand {
-    fn apply(self, world: &mut World) {
-        self.0.build(world, self.1);
+impl BuildTemplateCommand {
+    /// Creates a command which builds `template` onto `entity`.
+    pub fn new(template: Template, entity: Entity) -> Self {
+        BuildTemplateCommand(template, entity)
     }
 }
 
-impl EntityCommand for BuildTemplateCommand {
-    fn apply(self, mut entity_world_mut: EntityWorldMut) { 
-        let entity = entity_world_mut.id();
-        unsafe { 
-            let world = entity_world_mut.world_mut(); 
-            self.0.build(world, entity);
+impl Command<Result<(), TemplateBuildError>> for BuildTemplateCommand {
+    fn apply(self, world: &mut World) -> Result<(), TemplateBuildError> {
+        if world.get_entity(self.1).is_err() {
+            return Err(TemplateBuildError::EntityNotFound { entity: self.1 });
+        }
+        match world.get_resource_mut::<PendingBuilds>() {
+            Some(mut pending) => pending.queue(self.1, self.0),
+            None => self.0.build(world, self.1),
         }
+        Ok(())
+    }
+}
+
+impl EntityCommand<Result<(), TemplateBuildError>> for BuildTemplateCommand {
+    fn apply(self, entity_world_mut: EntityWorldMut) -> Result<(), TemplateBuildError> {
+        let entity = entity_world_mut.id();
+        Command::apply(
+            BuildTemplateCommand(self.0, entity),
+            entity_world_mut.into_world_mut(),
+        )
     }
 }
 
 pub trait CommandsTemplateExt {
     /// Builds a template. See [`BuildTemplate::build`] for more documentation.
-    fn build(&mut self, template: Template) -> EntityCommands;
+    fn build(&mut self, template: Template) -> EntityCommands<'_>;
 }
 
 impl<'w, 's> CommandsTemplateExt for Commands<'w, 's> {
-    fn build(&mut self, template: Template) -> EntityCommands {
+    /// Spawns a new entity and builds the template onto it.
+    fn build(&mut self, template: Template) -> EntityCommands<'_> {
         let entity_id = self.spawn_empty().id();
-        self.queue(BuildTemplateCommand(template, entity_id));
-        self.entity(entity_id)
+        self.build_to(template, entity_id)
     }
 }
 
-pub trait EntityCommandsTemplateExt {
-    fn build_to(&mut self, template: Template, entity: Entity) -> EntityCommands;
-}
-
 impl<'w> CommandsTemplateExt for EntityCommands<'w> {
-    fn build(&mut self, template: Template) -> EntityCommands {
+    /// Builds the template onto this entity.
+    fn build(&mut self, template: Template) -> EntityCommands<'_> {
         self.queue(BuildTemplateCommand(template, self.id()));
         self.reborrow()
     }
 }
 
+pub trait EntityCommandsTemplateExt {
+    /// Builds a template onto a specific existing entity, and returns the
+    /// [`EntityCommands`] for that entity. Building onto the same entity again
+    /// re-uses what the last build left behind. See [`BuildTemplate::build`]
+    /// for more documentation.
+    fn build_to(&mut self, template: Template, entity: Entity) -> EntityCommands<'_>;
+}
+
+impl<'w, 's> EntityCommandsTemplateExt for Commands<'w, 's> {
+    fn build_to(&mut self, template: Template, entity: Entity) -> EntityCommands<'_> {
+        self.queue(BuildTemplateCommand(template, entity));
+        self.entity(entity)
+    }
+}
+
 /// A fragment is a tree of bundles with optional names. It implements
 /// [`Prototype`] and can be stored or used as a `Box<dyn Prototype>`.
 #[derive(Clone)] // Require B to be Clone so the entire Fragment can be cloned.
 pub struct Fragment<B: Bundle + Clone> {
-    /// The name of the fragment, used to identify children across builds.
-    pub anchor: Option<String>,
+    /// The anchor of the fragment, used to identify children across builds.
+    pub anchor: Option<Anchor>,
     /// The bundle to be inserted on the entity.
     pub bundle: B,
     /// The template for the children. This boils down to a type-erased
     /// `Fragment` vector.
     pub children: Template,
+    /// The observers watching the entity.
+    pub observers: Vec<FragmentObserver>,
+    /// The context values provided to the entities below this one.
+    pub context: Vec<ContextProvider>,
+    /// The templates for the entities related to this one by relationships
+    /// other than [`ChildOf`]. There should be at most one per relationship.
+    pub related: Vec<RelatedTemplate>,
+    /// A bundle which refers to other entities in the template by name. If
+    /// this is set, it is inserted instead of `bundle` once the template has
+    /// been built and the entities are known.
+    pub resolve: Option<ResolveBundle<B>>,
+}
+
+impl<B: Bundle + Clone> Fragment<B> {
+    /// Attaches an observer to the fragment, which watches the entity it is
+    /// built on. See [`FragmentObserver`] for how observers are rebuilt.
+    pub fn on<E: Event, C: Bundle, M, I>(mut self, system: I) -> Self
+    where
+        I: IntoObserverSystem<E, C, M> + Clone + Sync,
+    {
+        self.observers.push(FragmentObserver::new(system));
+        self
+    }
+
+    /// Provides a value to the entities below the fragment, which widgets can
+    /// read with [`context`](crate::context). See [`ContextProvider`].
+    pub fn provide<T: Clone + PartialEq + Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.context.push(ContextProvider::new(value));
+        self
+    }
+
+    /// Adds a template for entities related to the fragment by `R`. If the
+    /// fragment already has one for `R`, the template is appended to it.
+    pub fn related<R: Relationship>(mut self, template: Template) -> Self
+    where
+        <R::RelationshipTarget as RelationshipTarget>::Collection:
+            OrderedRelationshipSourceCollection,
+    {
+        let kind = TypeId::of::<R>();
+        match self
+            .related
+            .iter_mut()
+            .find(|related| related.kind() == kind)
+        {
+            Some(related) => related.extend(template),
+            None => self.related.push(RelatedTemplate::new::<R>(template)),
+        }
+        self
+    }
 }
 
 impl<B: Bundle + Clone> Prototype for Fragment<B> {
     fn name(&self) -> Option<String> {
+        self.anchor.as_ref().map(ToString::to_string)
+    }
+
+    fn anchor(&self) -> Option<Anchor> {
         self.anchor.clone()
     }
 
+    fn kind(&self) -> Key {
+        Key::new(TypeId::of::<B>())
+    }
+
     fn build(self: Box<Self>, world: &mut World, entity: Entity) {
-        // Collect the set of components in the bundle
-        let mut components = HashSet::new();
-        B::get_component_ids(world.components(), &mut |maybe_id| {
-            if let Some(id) = maybe_id {
-                components.insert(id);
+        let Fragment {
+            bundle,
+            children,
+            observers,
+            context,
+            related,
+            resolve,
+            ..
+        } = *self;
+        build_fragment(
+            world,
+            entity,
+            children,
+            observers,
+            context,
+            related,
+            |entity_mut| {
+                // Insert the bundle, overwriting any previous values which
+                // changed.
+                match resolve {
+                    Some(resolve) => {
+                        entity_mut.world_scope(|world| insert_resolved(world, entity, resolve))
+                    }
+                    None => insert_changed(entity_mut, bundle),
+                }
+
+                // Collect the set of components in the bundle.
+                let mut components = HashSet::new();
+                B::get_component_ids(entity_mut.world().components(), &mut |maybe_id| {
+                    if let Some(id) = maybe_id {
+                        components.insert(id);
+                    }
+                });
+                components
+            },
+        );
+    }
+
+    fn diff(&self, world: &World, entity: Option<Entity>, diff: &mut TemplateDiff) {
+        // Collect the set of components in the bundle. Components which have
+        // never been registered can't be on the entity yet.
+        let mut components = BundleComponents::default();
+        B::get_component_ids(world.components(), &mut |maybe_id| match maybe_id {
+            Some(id) => {
+                components.ids.insert(id);
             }
+            None => components.unregistered += 1,
         });
+        diff_fragment(
+            world,
+            entity,
+            diff,
+            components,
+            &self.children,
+            &self.observers,
+            &self.related,
+        );
+    }
+}
+
+/// Builds the parts shared by every kind of fragment onto an entity: its
+/// children, observers, context values and related entities. `insert` inserts
+/// the fragment's components, and returns the ids of every component it is
+/// made of; components inserted by the previous build but not this one are
+/// removed.
+pub(crate) fn build_fragment(
+    world: &mut World,
+    entity: Entity,
+    children: Template,
+    observers: Vec<FragmentObserver>,
+    context: Vec<ContextProvider>,
+    related: Vec<RelatedTemplate>,
+    insert: impl FnOnce(&mut EntityWorldMut) -> HashSet<ComponentId>,
+) {
+    remove_widget(world, entity);
+
+    // Take the receipt left behind by the previous build, if there was one.
+    let receipt = world
+        .get_mut::<Receipt>(entity)
+        .map(|mut receipt| core::mem::take(receipt.as_mut()))
+        .unwrap_or_default();
+
+    // Build the children and related entities with the context values the
+    // fragment provides.
+    let mut previous_related = receipt.related;
+    let ((children, anchors), related, related_entities) =
+        provide(world, entity, context, |world| {
+            // Build the children, re-using the previous children where
+            // possible.
+            let children = build_children(world, children, receipt.anchors);
 
-        // Build the children
-        let num_children = self.children.len();
-        let mut children = Vec::with_capacity(num_children);
-        for child in self.children {
-            // Build the child
-            let child_entity = world.spawn_empty().id();
-            child.build(world, child_entity);
-            children.push(child_entity);
+            // Build the related entities of each relationship in the same
+            // way.
+            let mut related_anchors = HashMap::with_capacity(related.len());
+            let mut related_entities = Vec::with_capacity(related.len());
+            for mut related_template in related {
+                let kind = related_template.kind();
+                let previous_anchors = previous_related.remove(&kind).unwrap_or_default();
+                let template = related_template.take_template();
+                let (entities, anchors) = build_children(world, template, previous_anchors);
+                related_anchors.insert(kind, anchors);
+                related_entities.push((related_template, entities));
+            }
+            // Building an empty template despawns every previous entity,
+            // which removes the relationships which are no longer part of
+            // the template.
+            for previous_anchors in previous_related.into_values() {
+                build_children(world, Template::new(), previous_anchors);
+            }
+            (children, related_anchors, related_entities)
+        });
+
+    // Insert the components, and remove the ones which were inserted by the
+    // previous build but not this one.
+    let mut entity_mut = world.entity_mut(entity);
+    let components = insert(&mut entity_mut);
+    let stale_components = receipt
+        .components
+        .difference(&components)
+        .copied()
+        .collect::<Vec<_>>();
+    entity_mut.remove_by_ids(&stale_components);
+
+    // Spawn the observers, re-using the previous ones where possible.
+    let observers = entity_mut
+        .world_scope(|world| build_observers(world, entity, observers, receipt.observers));
+
+    // Re-order the children and related entities in place to match the
+    // template, and store the new receipt. Any memo input is cleared, since
+    // the entity is no longer built by that memo.
+    for (related_template, entities) in &related_entities {
+        related_template.relate(&mut entity_mut, entities);
+    }
+    entity_mut.insert_children(0, &children).insert(Receipt {
+        components,
+        anchors,
+        observers,
+        related,
+        memo: None,
+        ..receipt
+    });
+}
+
+/// The components a fragment is made of, as seen by a [`TemplateDiff`].
+#[derive(Default)]
+pub(crate) struct BundleComponents {
+    /// The ids of the components which have been registered with the world.
+    pub(crate) ids: HashSet<ComponentId>,
+    /// The number of components which have never been registered, and so
+    /// can't be on any entity yet.
+    pub(crate) unregistered: usize,
+}
+
+/// Records the changes building a fragment made of `components` onto `entity`
+/// would make. The counterpart of [`build_fragment`].
+pub(crate) fn diff_fragment(
+    world: &World,
+    entity: Option<Entity>,
+    diff: &mut TemplateDiff,
+    components: BundleComponents,
+    children: &Template,
+    observers: &[FragmentObserver],
+    related: &[RelatedTemplate],
+) {
+    let BundleComponents { ids, unregistered } = components;
+    let mut inserted = ids
+        .iter()
+        .copied()
+        .filter(|id| !entity.is_some_and(|entity| world.entity(entity).contains_id(*id)))
+        .collect::<Vec<_>>();
+    inserted.sort();
+    let receipt = entity.and_then(|entity| world.get::<Receipt>(entity));
+    let mut removed = receipt
+        .map(|receipt| {
+            receipt
+                .components
+                .difference(&ids)
+                .copied()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    removed.sort();
+
+    let previous_observers = receipt
+        .map(|receipt| receipt.observers.clone())
+        .unwrap_or_default();
+    let (observers, stale_observers) = diff_observers(world, observers, previous_observers);
+    diff.despawned.extend(stale_observers);
+
+    let index = diff.entities.len();
+    diff.entities.push(EntityDiff {
+        path: diff.path().to_vec(),
+        entity,
+        inserted,
+        unregistered,
+        removed,
+        observers,
+        reordered: false,
+    });
+    let mut reordered = diff.children(world, entity, children);
+    for related_template in related {
+        reordered |= diff.related(world, entity, related_template);
+    }
+    for (kind, previous_anchors) in receipt.iter().flat_map(|receipt| &receipt.related) {
+        if !related
+            .iter()
+            .any(|related_template| related_template.kind() == *kind)
+        {
+            diff.despawned_anchors(world, previous_anchors);
+        }
+    }
+    diff.entities[index].reordered = reordered;
+}
+
+/// A child prototype, matched to the entity it should be built on.
+pub(crate) struct ChildMatch {
+    /// The anchor of the child among its siblings.
+    pub(crate) anchor: Anchor,
+    /// The kind of the child prototype.
+    pub(crate) kind: Key,
+    /// The entity built for the same anchor last time, or `None` if a new
+    /// entity should be spawned.
+    pub(crate) entity: Option<Entity>,
+}
+
+/// Matches a list of child prototypes to the entities built for the same
+/// anchors last time. Unnamed prototypes whose kind has changed since the last
+/// build are not matched, so that they are built on fresh entities.
+///
+/// Duplicate and missing anchors are pushed to `errors`, and duplicates are
+/// treated as if they were unnamed.
+pub(crate) fn match_children(
+    world: &World,
+    template: &[Box<dyn Prototype + Send + Sync>],
+    previous_anchors: &HashMap<Anchor, Anchored>,
+    path: &[Anchor],
+    errors: &mut Vec<TemplateBuildError>,
+) -> Vec<ChildMatch> {
+    // Collect the kinds of the dynamically named prototypes, so that we can
+    // detect unnamed siblings which are probably missing a name.
+    let dynamic_kinds = template
+        .iter()
+        .filter(|child| child.anchor().is_some_and(|anchor| is_dynamic(&anchor)))
+        .map(|child| child.kind())
+        .collect::<HashSet<_>>();
+
+    let mut matches = Vec::<ChildMatch>::with_capacity(template.len());
+    let mut anchors = HashSet::with_capacity(template.len());
+    let mut unnamed = 0;
+    for child in template {
+        let kind = child.kind();
+        let mut anchor = child.anchor();
+
+        // Number repeated scopes, and report other duplicate anchors, treating
+        // the duplicate as unnamed.
+        if let Some(anchor) = &mut anchor {
+            number_occurrence(anchor, &anchors);
+        }
+        if let Some(duplicate) = anchor.take_if(|anchor| anchors.contains(anchor)) {
+            errors.push(TemplateBuildError::DuplicateAnchor {
+                path: path.to_vec(),
+                anchor: duplicate,
+            });
+        }
+
+        let anchor = anchor.unwrap_or_else(|| {
+            unnamed += 1;
+            Anchor::Auto(unnamed - 1)
+        });
+        if matches!(anchor, Anchor::Auto(_)) && dynamic_kinds.contains(&kind) {
+            errors.push(TemplateBuildError::MissingKey {
+                path: path.to_vec(),
+                anchor: anchor.clone(),
+            });
+        }
+
+        // Find the entity built for this anchor last time, if it can be re-used.
+        let entity = previous_anchors
+            .get(&anchor)
+            .filter(|previous| world.get_entity(previous.entity).is_ok())
+            .filter(|previous| previous.kind == kind || !anchor.is_positional())
+            .map(|previous| previous.entity);
+
+        anchors.insert(anchor.clone());
+        matches.push(ChildMatch {
+            anchor,
+            kind,
+            entity,
+        });
+    }
+    matches
+}
+
+/// Returns true if the anchor is computed when the template is created, such as
+/// the key of an element of a `@for` list.
+fn is_dynamic(anchor: &Anchor) -> bool {
+    match anchor {
+        Anchor::Dynamic(_) | Anchor::Keyed(_) => true,
+        Anchor::Scoped(_, _, anchor) => is_dynamic(anchor),
+        _ => false,
+    }
+}
+
+/// Gives a scoped anchor which is already taken by a sibling the next free
+/// occurrence of its scope, so that a scope spliced in more than once among the
+/// same siblings doesn't collide with itself.
+fn number_occurrence(anchor: &mut Anchor, anchors: &HashSet<Anchor>) {
+    while anchors.contains(anchor) {
+        let Anchor::Scoped(_, occurrence, _) = anchor else {
+            return;
+        };
+        *occurrence += 1;
+    }
+}
+
+/// Builds a list of child prototypes, matching each one to the entity that was
+/// built for the same anchor last time. New entities are spawned for anchors
+/// that don't have one yet, and the entities of anchors which are no longer
+/// present are despawned. See [`match_children`] for how children are matched.
+///
+/// Duplicate and missing anchors are reported as [`TemplateBuildError`]s.
+///
+/// Returns the children in template order, along with their anchors.
+fn build_children(
+    world: &mut World,
+    template: Template,
+    mut previous_anchors: HashMap<Anchor, Anchored>,
+) -> (Vec<Entity>, HashMap<Anchor, Anchored>) {
+    let mut errors = Vec::new();
+    let path = BuildPath::current(world);
+    let matches = match_children(world, &template, &previous_anchors, &path, &mut errors);
+    for error in errors {
+        report_error(world, error);
+    }
+
+    let mut children = Vec::with_capacity(template.len());
+    let mut anchors = HashMap::with_capacity(template.len());
+    for (
+        child,
+        ChildMatch {
+            anchor,
+            kind,
+            entity,
+        },
+    ) in template.into_iter().zip(matches)
+    {
+        // Re-use the entity built for this anchor last time, or spawn a new one.
+        let child_entity = match entity {
+            Some(entity) => {
+                previous_anchors.remove(&anchor);
+                entity
+            }
+            None => world.spawn_empty().id(),
+        };
+
+        // Build the child, keeping track of the path to it.
+        record_reference(world, &anchor, child_entity);
+        world
+            .get_resource_or_init::<BuildPath>()
+            .0
+            .push(anchor.clone());
+        child.build(world, child_entity);
+        world.resource_mut::<BuildPath>().0.pop();
+
+        children.push(child_entity);
+        anchors.insert(
+            anchor,
+            Anchored {
+                entity: child_entity,
+                kind,
+            },
+        );
+    }
+
+    // Despawn the children which are no longer part of the template.
+    for previous in previous_anchors.into_values() {
+        despawn_built(world, previous.entity);
+    }
+
+    (children, anchors)
+}
+
+/// Despawns an entity built by a template recursively, along with the entities
+/// built for it or its descendants which Bevy wouldn't despawn with it: those
+/// related to them by relationships other than [`ChildOf`], and the additional
+/// roots built onto them.
+pub(crate) fn despawn_built(world: &mut World, entity: Entity) {
+    // Collect the entities recorded in the receipts of the whole subtree before
+    // it is despawned.
+    let mut built = Vec::new();
+    let mut stack = vec![entity];
+    while let Some(next) = stack.pop() {
+        let Ok(entity_ref) = world.get_entity(next) else {
+            continue;
+        };
+        if let Some(children) = entity_ref.get::<Children>() {
+            stack.extend(children.iter());
+        }
+        if let Some(receipt) = entity_ref.get::<Receipt>() {
+            let related = receipt.related.values().flat_map(HashMap::values);
+            built.extend(
+                related
+                    .chain(receipt.roots.iter().flat_map(HashMap::values))
+                    .map(|anchored| anchored.entity),
+            );
         }
+    }
 
-        // Get or spawn the entity, insert the bundle, and add the children.
-        world.entity_mut(entity)
-            .insert(self.bundle)
-            .add_children(&children);
+    if let Ok(entity_mut) = world.get_entity_mut(entity) {
+        entity_mut.despawn();
+    }
+    for entity in built {
+        despawn_built(world, entity);
+    }
+}
+
+/// Scopes the anchors of every prototype in a template under a key, so that they
+/// can't collide with the anchors of their siblings once the template is spliced
+/// into a list. Unnamed prototypes are numbered within the scope, so adding or
+/// removing a scoped template doesn't shift the positions of unnamed siblings.
+///
+/// This is used to give each branch of an `@if` in [`template`] its own
+/// identity, but can also be used directly with splices. If the same scope
+/// ends up among the same siblings more than once, such as when a helper
+/// returning a scoped template is spliced twice, each occurrence is numbered in
+/// order, as described in [`Anchor::Scoped`].
+pub fn scoped(scope: Key, template: Template) -> Template {
+    let mut unnamed = 0;
+    template
+        .into_iter()
+        .map(|prototype| {
+            let anchor = prototype.anchor().unwrap_or_else(|| {
+                unnamed += 1;
+                Anchor::Auto(unnamed - 1)
+            });
+            Box::new(Reanchored {
+                anchor: Anchor::Scoped(scope.clone(), 0, Box::new(anchor)),
+                prototype,
+            }) as Box<_>
+        })
+        .collect()
+}
+
+/// A prototype with its anchor replaced. See [`scoped`].
+#[derive(Clone)]
+struct Reanchored {
+    anchor: Anchor,
+    prototype: Box<dyn Prototype + Send + Sync>,
+}
+
+impl Prototype for Reanchored {
+    fn name(&self) -> Option<String> {
+        Some(self.anchor.to_string())
+    }
+
+    fn anchor(&self) -> Option<Anchor> {
+        Some(self.anchor.clone())
+    }
+
+    fn kind(&self) -> Key {
+        self.prototype.kind()
+    }
+
+    fn build(self: Box<Self>, world: &mut World, entity: Entity) {
+        self.prototype.build(world, entity);
+    }
+
+    fn diff(&self, world: &World, entity: Option<Entity>, diff: &mut TemplateDiff) {
+        self.prototype.diff(world, entity, diff);
     }
 }
 
@@ -203,7 +1365,7 @@ impl<B: Bundle + Clone> IntoIterator for Fragment<B> {
 /// # use i_cant_believe_its_not_bsn::*;
 /// # use bevy::prelude::*;
 /// # let dark_mode = false;
-/// # #[derive(Component)]
+/// # #[derive(Component, Clone)]
 /// # pub struct MyMarkerComponent;
 /// template! {
 ///     {(
@@ -252,6 +1414,214 @@ impl<B: Bundle + Clone> IntoIterator for Fragment<B> {
 /// };
 /// ```
 ///
+/// # Lists
+///
+/// Lists of fragments can be generated from an iterator with `@for`. Each
+/// element is given a key, which can be any value implementing `Hash + Eq`. Keys
+/// are used to match elements to the same entities across builds, so elements
+/// can be inserted, removed and re-ordered cheaply. Keys only need to be unique
+/// within their list, so two lists among the same siblings may share keys. Like
+/// fragments, lists can have children and must be followed by a semicolon.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// # struct Item { id: u32, name: &'static str }
+/// let items = vec![Item { id: 7, name: "sword" }, Item { id: 3, name: "shield" }];
+/// template! {
+///     { Node::default() } [
+///         @for item in items.iter() => key(item.id) { Text::new(item.name) } [
+///             { TextSpan::new("!") };
+///         ];
+///     ];
+/// };
+/// ```
+///
+/// # Conditionals
+///
+/// Fragments can be included conditionally with `@if`, optionally followed by an
+/// `@else`. Conditions can also be `let` patterns, which bind variables for the
+/// first branch. Each branch has its own identity, so toggling a condition only
+/// despawns the entities of the branch which is no longer taken, and doesn't
+/// disturb unnamed siblings.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// # let logged_in = true;
+/// # let message: Option<&str> = None;
+/// template! {
+///     @if logged_in {
+///         { Text::new("Welcome back!") };
+///     } @else {
+///         { Text::new("Please log in.") };
+///         { Button };
+///     };
+///     @if let Some(message) = message {
+///         { Text::new(message) };
+///     };
+/// };
+/// ```
+///
+/// Fragments can also be chosen with `@match`. Each arm contains a list of
+/// fragments, and has its own identity, so switching between arms replaces the
+/// entities of the old arm rather than building the new arm on top of them.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// # enum LoadState { Loading(f32), Failed(String), Ready }
+/// # let state = LoadState::Loading(0.5);
+/// template! {
+///     @match &state {
+///         LoadState::Loading(progress) => {
+///             { Text::new(format!("Loading {}%", progress * 100.0)) };
+///         },
+///         LoadState::Failed(error) => {
+///             { Text::new(error.clone()) };
+///         },
+///         LoadState::Ready => {},
+///     };
+/// };
+/// ```
+///
+/// # Widgets
+///
+/// Types implementing [`Widget`] can be added with `@widget`, followed by a block
+/// returning the widget. Each widget is a single prototype which renders its own
+/// template, and like fragments it can be named. Widgets can keep local state on
+/// their entity with [`state`]. See [`Widget`] for an example.
+///
+/// # Memos
+///
+/// A part of a template which is expensive to build can be wrapped in
+/// `@memo(input) { ... }`, where the input is any `Hash + Eq` value the part
+/// depends on. The part is built on a single entity, like a widget, and is only
+/// rebuilt when the input changes: otherwise the build is skipped and its
+/// entities are left untouched. See [`Memo`] for more information.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// let items = vec!["sword", "shield"];
+/// template! {
+///     inventory: @memo(items.clone()) {
+///         { Node::default() } [
+///             @for item in items.iter() => key(*item) { Text::new(*item) };
+///         ];
+///     };
+/// };
+/// ```
+///
+/// # References
+///
+/// A fragment's block can refer to the entity of another statically named
+/// fragment in the same template with `#name`, which evaluates to its `Entity`.
+/// Since the template is created before any entities are built, blocks with
+/// references are run again once the whole template has been built, so they
+/// can only capture values which are owned and cloneable. References are
+/// resolved on every build, so they keep pointing at the right entity when the
+/// target is re-used or respawned.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// #[derive(Component, Clone)]
+/// struct Scrollbar {
+///     target: Entity,
+/// }
+///
+/// template! {
+///     { Node::default() } [
+///         scroll_area: { Node::default() };
+///         { Scrollbar { target: #scroll_area } };
+///     ];
+/// };
+/// ```
+///
+/// Fragments inside an `@memo` are part of the template the memo is in, so
+/// references can cross into and out of it, even while the memo skips its
+/// build. A widget renders a template of its own, so references can't cross
+/// into or out of it. References to names which no fragment has, or which more
+/// than one fragment has, are reported as a [`TemplateBuildError`].
+///
+/// # Relationships
+///
+/// Children are related to their parent by [`ChildOf`]. Entities can be related
+/// by any other ordered [`Relationship`] by adding a section after the children,
+/// which starts with the relationship type and `=>`. Related entities are matched
+/// and ordered across builds in the same way as children.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// #[derive(Component)]
+/// #[relationship(relationship_target = Contains)]
+/// struct ContainedBy(Entity);
+///
+/// #[derive(Component)]
+/// #[relationship_target(relationship = ContainedBy)]
+/// struct Contains(Vec<Entity>);
+///
+/// # #[derive(Component, Clone)]
+/// # struct Chest;
+/// # #[derive(Component, Clone)]
+/// # struct Item(&'static str);
+/// template! {
+///     { Chest } [
+///         { Text::new("Chest") };
+///     ] [ContainedBy =>
+///         { Item("sword") };
+///         { Item("shield") };
+///     ];
+/// };
+/// ```
+///
+/// # Observers
+///
+/// Observers can be attached to a fragment with `on(...)` after its block, which
+/// takes an observer system like [`EntityWorldMut::observe`]. The observers watch
+/// the entity the fragment is built on, and are only spawned once: they are
+/// matched across builds by the type of their system. Lists can have observers
+/// too.
+///
+/// Because the observer spawned by the first build keeps running, a closure
+/// which captures values from the template, such as props, keeps the values it
+/// captured then. To have the observer respawned when those values change, key
+/// it on them with `on(system => key(value))`. See [`FragmentObserver`].
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// template! {
+///     { Button } on(|trigger: Trigger<Pointer<Click>>| {
+///         info!("{} was clicked", trigger.target());
+///     }) [
+///         { Text::new("Click me") };
+///     ];
+/// };
+/// ```
+///
+/// # Context
+///
+/// A fragment can provide values to everything below it with `provide(...)`
+/// after its block, which widgets read with [`context`](crate::context). The
+/// nearest provider of each type wins, and when a provided value changes, the
+/// widgets which read it are rebuilt.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// #[derive(Clone, PartialEq)]
+/// struct Locale(&'static str);
+///
+/// template! {
+///     { Node::default() } provide(Locale("en-US")) [
+///         { Text::new("Hello") };
+///     ];
+/// };
+/// ```
+///
 /// # Names
 ///
 /// Fragments can be optionally prefixed by a name. A name is either literal
@@ -270,16 +1640,22 @@ impl<B: Bundle + Clone> IntoIterator for Fragment<B> {
 ///
 /// Most fragments don't need names, but you should give fragments unique names in
 /// certain cases (for example when entities only appear conditionally or when children
-/// may be re-ordered between builds).
+/// may be re-ordered between builds). Siblings which share a name, and unnamed
+/// fragments with the same bundle type as a dynamically named or keyed sibling, are
+/// reported as a [`TemplateBuildError`] when the template is built.
+///
+/// # Errors
+///
+/// Syntax errors, such as a missing semicolon, are reported at the fragment they
+/// occur in. Blocks which don't return the expected type are reported at the
+/// block itself.
 ///
 /// # Limitations
 ///
-/// This macro is fairly limited, and its implementation is less than 50 lines.
-/// You should expect to run into a few pain points, such as:
+/// This macro is fairly limited. You should expect to run into a few pain
+/// points, such as:
 /// + Each fragment must have a statically defined bundle type.
-/// + The syntax for optional or conditional fragments is cumbersome (you have to use splices).
-/// + You are responsible for ensuring dynamic fragments are named properly (no warnings if you don't).
-/// + It's hard to customize how templates are built or to build them on specific entities.
+/// + It's hard to customize how templates are built.
 ///
 /// All of these can (and hopefully will) be addressed in a future version.
 ///
@@ -289,66 +1665,745 @@ impl<B: Bundle + Clone> IntoIterator for Fragment<B> {
 ///
 /// ```ignore
 ///      <template> = *( <item> )
-///          <item> = ( <splice> | <fragment> ) ";"
+///          <item> = ( <splice> | <list> | <conditional> | <match> | <widget> | <memo> | <fragment> ) ";"
 ///        <splice> = "@" <$block>                      -- where block returns `T: IntoIterator<Item = Box<dyn Prototype>>`.
-///      <fragment> = <name>? <$block> <children>?      -- where block returns `B: Bundle`.
+///          <list> = "@for" <$pat> "in" <$expr> "=>" "key(" <$expr> ")" <body>
+///                                                     -- where the key returns `K: Hash + Eq` and block returns `B: Bundle`.
+///   <conditional> = "@if" <$cond> "{" <template> "}" ( "@else" "{" <template> "}" )?
+///                                                     -- where cond is a boolean expression or `let` pattern.
+///         <match> = "@match" <$expr> "{" <arm> *( "," <arm> ) ","? "}"
+///           <arm> = <$pat> ( "if" <$expr> )? "=>" "{" <template> "}"
+///        <widget> = <name>? "@widget" <$block>      -- where block returns `W: Widget`.
+///          <memo> = <name>? "@memo" "(" <$expr> ")" "{" <template> "}"
+///                                                     -- where expr returns `T: Hash + Eq`.
+///      <fragment> = <name>? <body>
+///          <body> = <$block> *( <observer> | <provide> ) <children>? *( <related> )
+///                                                     -- where block returns `B: Bundle`.
 ///          <name> = ( <$ident> | <$block> ) ":"       -- where block returns `D: Display`.
-///      <children> = "[" <template> "]"           
+///      <observer> = "on" "(" <$expr> ( "=>" "key(" <$expr> ")" )? ")"
+///                                                     -- where expr is an observer system and the key returns `K: Hash + Eq`.
+///       <provide> = "provide" "(" <$expr> ")"         -- where expr returns `T: Clone + PartialEq`.
+///      <children> = "[" <template> "]"
+///       <related> = "[" <$path> "=>" <template> "]"  -- where path is a `Relationship`.
 ///        <$ident> = an opaque rust identifier
-///        <$block> = a rust codeblock of a given type
+///         <$path> = a rust type path
+///          <$pat> = a rust pattern
+///         <$cond> = the condition of a rust `if` expression
+///         <$expr> = a rust expression
+///        <$block> = a rust codeblock of a given type, which may contain `#name` references
 /// ```
 ///
-#[macro_export]
-macro_rules! template {
-    ($($tail:tt)*) => {{
-        #[allow(unused_mut)]
-        let mut fragments = Vec::new();
-        push_item!(fragments; $($tail)*);
-        fragments
-    }};
-}
-
-/// Used internally. See `template!()`.
-#[macro_export]
-macro_rules! push_item {
-    // Handle the empty cases.
-    () => {};
-    ($fragments:ident;) => {};
-    // Handle the case when no name is specified.
-    ($fragments:ident; $block:block $( [ $( $children:tt )+ ] )? ; $( $($sib:tt)+ )?) => {
-        push_fragment!($fragments; { None } $block $( [ $( $children )* ] )* ; $( $( $sib )* )* )
-    };
-    // Handle the fully specified case, when the name is a static identifier.
-    ($fragments:ident; $name:ident: $block:block $( [ $( $children:tt )+ ] )? ; $( $($sib:tt)+ )?) => {
-        // Stringify the name and throw it in a code block.
-        push_fragment!($fragments; { Some(stringify!($name).to_string()) } $block $( [ $( $children )* ] )* ; $( $( $sib )* )* )
-    };
-    // Handle the fully specified case, when the name is also a code block.
-    ($fragments:ident; $name:block: $block:block $( [ $( $children:tt )+ ] )? ; $( $($sib:tt)+ )?) => {
-        push_fragment!($fragments; { Some($name.to_string()) } $block $( [ $( $children )* ] )* ; $( $( $sib )* )* )
-    };
-    // Handle the case where it's just a code block, returning an iterator of prototypes.
-    ($fragments:ident; @ $block:block ; $( $($sib:tt)+ )? ) => {
-        $fragments.extend({ $block }); // Extend the fragments with the value of the block.
-        $( push_item!($fragments; $($sib)* ); )? // Continue pushing siblings.
-    };
+pub use i_cant_believe_its_not_bsn_macros::template;
+
+/// Used by the code generated by [`template`]. Not public API.
+#[doc(hidden)]
+pub mod __private {
+    use bevy_ecs::bundle::Bundle;
+
+    /// Passes a bundle through unchanged, so that a fragment whose block doesn't
+    /// return a bundle is reported at the block.
+    pub fn bundle<B: Bundle + Clone>(bundle: B) -> B {
+        bundle
+    }
+
+    /// Passes a widget through unchanged, so that a block which doesn't return
+    /// a widget is reported at the block.
+    pub fn widget<W: crate::Widget>(widget: W) -> W {
+        widget
+    }
 }
 
-/// Used internally. See `template!()`.
-#[macro_export]
-macro_rules! push_fragment {
-    ($fragments:ident; $anchor:block $bundle:block $( [ $( $children:tt )+ ] )? ; $( $($sib:tt)+ )?) => {
-        let fragment = Fragment {
-            anchor: $anchor,
-            bundle: $bundle,
-            children: {
-                #[allow(unused_mut)]
-                let mut fragments = Vec::new();
-                $( push_item!(fragments; $($children)*); )* // Push the children.
-                fragments
-            },
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct A;
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct B(u8);
+
+    #[test]
+    fn rebuild_reuses_entities() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let template = template! {
+            { A } [
+                { B(0) } [
+                    { B(1) };
+                ];
+                { B(2) };
+            ];
         };
-        $fragments.push(Box::new(fragment) as Box::<_>);
-        $( push_item!( $fragments; $($sib)* ); )* // Continue with siblings.
-    };
+        template.clone().build(&mut world, root);
+        let entity_count = world.entities().len();
+        let children = world.get::<Children>(root).unwrap().to_vec();
+
+        template.build(&mut world, root);
+        assert_eq!(world.entities().len(), entity_count);
+        assert_eq!(world.get::<Children>(root).unwrap().to_vec(), children);
+    }
+
+    #[test]
+    fn rebuild_removes_stale_components() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! { {(A, B(0))}; }.build(&mut world, root);
+        assert_eq!(world.get::<A>(root), Some(&A));
+        assert_eq!(world.get::<B>(root), Some(&B(0)));
+
+        template! { { B(1) }; }.build(&mut world, root);
+        assert_eq!(world.get::<A>(root), None);
+        assert_eq!(world.get::<B>(root), Some(&B(1)));
+    }
+
+    #[test]
+    fn rebuild_despawns_stale_children() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! {
+            { A } [
+                { B(0) };
+                { B(1) } [
+                    { B(2) };
+                ];
+            ];
+        }
+        .build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        let grandchild = world.get::<Children>(children[1]).unwrap()[0];
+
+        template! {
+            { A } [
+                { B(0) };
+            ];
+        }
+        .build(&mut world, root);
+        assert_eq!(
+            world.get::<Children>(root).unwrap().to_vec(),
+            &children[..1]
+        );
+        assert!(world.get_entity(children[1]).is_err());
+        assert!(world.get_entity(grandchild).is_err());
+    }
+
+    #[test]
+    fn rebuild_matches_named_children() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! {
+            { A } [
+                first: { B(0) };
+                second: { B(1) };
+            ];
+        }
+        .build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+
+        // Add some external state, which should survive the rebuild.
+        world.entity_mut(children[0]).insert(A);
+
+        template! {
+            { A } [
+                second: { B(1) };
+                first: { B(2) };
+            ];
+        }
+        .build(&mut world, root);
+        assert_eq!(
+            world.get::<Children>(root).unwrap().to_vec(),
+            [children[1], children[0]]
+        );
+        assert_eq!(world.get::<B>(children[0]), Some(&B(2)));
+        assert_eq!(world.get::<A>(children[0]), Some(&A));
+    }
+
+    #[test]
+    fn build_roots_on_separate_entities() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! {
+            a: { A };
+            b: { B(0) };
+            { B(1) };
+        }
+        .build(&mut world, root);
+        assert_eq!(world.get::<A>(root), Some(&A));
+        assert_eq!(world.get::<B>(root), None);
+
+        let receipt = world.get::<Receipt>(root).unwrap();
+        assert_eq!(receipt.roots().count(), 2);
+        let b = receipt
+            .roots()
+            .find(|(anchor, _)| **anchor == Anchor::Named("b".into()))
+            .unwrap()
+            .1;
+        assert_eq!(world.get::<B>(b), Some(&B(0)));
+        assert_eq!(world.get::<A>(b), None);
+        let entity_count = world.entities().len();
+
+        template! {
+            a: { A };
+            b: { B(2) };
+        }
+        .build(&mut world, root);
+        assert_eq!(world.entities().len(), entity_count - 1);
+        assert_eq!(world.get::<B>(b), Some(&B(2)));
+    }
+
+    #[test]
+    fn first_root_is_positional() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! {
+            a: { A };
+            b: { B(0) };
+        }
+        .build(&mut world, root);
+        let b = world
+            .get::<Receipt>(root)
+            .unwrap()
+            .roots()
+            .next()
+            .unwrap()
+            .1;
+
+        // `b` is now built on the root itself, and `a` on a new entity.
+        template! {
+            b: { B(0) };
+            a: { A };
+        }
+        .build(&mut world, root);
+        assert_eq!(world.get::<B>(root), Some(&B(0)));
+        assert_eq!(world.get::<A>(root), None);
+        assert!(world.get_entity(b).is_err());
+        let a = world.get::<Receipt>(root).unwrap().roots().next().unwrap();
+        assert_eq!(a.0, &Anchor::Named("a".into()));
+        assert_eq!(world.get::<A>(a.1), Some(&A));
+    }
+
+    #[test]
+    fn build_to_existing_entity() {
+        use bevy_ecs::system::RunSystemOnce;
+
+        let mut world = World::new();
+        let panel = world.spawn(B(0)).id();
+
+        let build_panel = move |mut commands: Commands| {
+            commands
+                .build_to(template! { {A} [ { B(1) }; ]; }, panel)
+                .insert(B(2));
+        };
+        world.run_system_once(build_panel).unwrap();
+        let children = world.get::<Children>(panel).unwrap().to_vec();
+        assert_eq!(world.get::<A>(panel), Some(&A));
+        assert_eq!(world.get::<B>(panel), Some(&B(2)));
+        assert_eq!(world.get::<B>(children[0]), Some(&B(1)));
+
+        world.run_system_once(build_panel).unwrap();
+        assert_eq!(world.get::<Children>(panel).unwrap().to_vec(), children);
+    }
+
+    #[test]
+    fn rebuild_matches_unnamed_children_by_position() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! {
+            { A } [
+                { B(0) };
+                named: { B(1) };
+                { B(2) };
+            ];
+        }
+        .build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+
+        // Removing a named sibling doesn't disturb the unnamed ones.
+        template! {
+            { A } [
+                { B(0) };
+                { B(2) };
+            ];
+        }
+        .build(&mut world, root);
+        assert_eq!(
+            world.get::<Children>(root).unwrap().to_vec(),
+            [children[0], children[2]]
+        );
+
+        // Changing the bundle type at a position respawns the entity.
+        template! {
+            { A } [
+                { B(0) };
+                { A };
+            ];
+        }
+        .build(&mut world, root);
+        let new_children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(new_children[0], children[0]);
+        assert_ne!(new_children[1], children[2]);
+        assert!(world.get_entity(children[2]).is_err());
+        assert_eq!(world.get::<B>(new_children[1]), None);
+    }
+
+    #[test]
+    fn duplicate_anchors_are_reported() {
+        let mut world = World::new();
+        world.insert_resource(TemplateErrorMode::Log);
+        let root = world.spawn_empty().id();
+
+        template! {
+            { A } [
+                parent: { A } [
+                    child: { B(0) };
+                    child: { B(1) };
+                ];
+            ];
+        }
+        .build(&mut world, root);
+        let parent = world.get::<Children>(root).unwrap()[0];
+        assert_eq!(world.get::<Children>(parent).unwrap().len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate anchor `child` under `/parent`")]
+    fn duplicate_anchors_panic() {
+        let mut world = World::new();
+        world.insert_resource(TemplateErrorMode::Panic);
+        let root = world.spawn_empty().id();
+
+        template! {
+            { A } [
+                parent: { A } [
+                    {"child"}: { B(0) };
+                    { A };
+                    {"child"}: { B(1) };
+                ];
+            ];
+        }
+        .build(&mut world, root);
+    }
+
+    #[test]
+    fn missing_keys_dont_panic() {
+        let mut world = World::new();
+        world.insert_resource(TemplateErrorMode::Panic);
+        let root = world.spawn_empty().id();
+
+        // An unnamed header followed by a list of the same kind is valid.
+        let template = template! {
+            { A } [
+                { B(0) };
+                @for i in 1..3 => key(i) { B(i) };
+            ];
+        };
+        let diff = TemplateDiff::new(&world, root, &template).unwrap();
+        assert!(matches!(
+            diff.errors.as_slice(),
+            [TemplateBuildError::MissingKey { .. }]
+        ));
+        template.build(&mut world, root);
+        assert_eq!(world.get::<Children>(root).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn try_build_despawned_entity() {
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+        world.despawn(entity);
+
+        let result = template! { { A }; }.try_build(&mut world, entity);
+        assert_eq!(result, Err(TemplateBuildError::EntityNotFound { entity }));
+    }
+
+    #[test]
+    fn build_command_error_handling() {
+        use bevy_ecs::{error::ignore, system::RunSystemOnce};
+
+        let mut world = World::new();
+        let entity = world.spawn_empty().id();
+        world
+            .run_system_once(move |mut commands: Commands| {
+                commands.entity(entity).despawn();
+                commands.queue_handled(
+                    BuildTemplateCommand::new(template! { { A }; }, entity),
+                    ignore,
+                );
+            })
+            .unwrap();
+        assert!(world.get_entity(entity).is_err());
+    }
+
+    #[test]
+    fn diff_does_not_modify_world() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        template! {
+            {(A, B(0))} [
+                first: { B(1) };
+                second: { B(2) };
+                third: { B(3) };
+            ];
+        }
+        .build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        let entity_count = world.entities().len();
+
+        let template = template! {
+            { B(0) } [
+                third: { B(3) };
+                first: { (A, B(1)) };
+                fourth: { B(4) };
+            ];
+        };
+        let diff = TemplateDiff::new(&world, root, &template).unwrap();
+        assert_eq!(world.entities().len(), entity_count);
+
+        let a = world.component_id::<A>().unwrap();
+        assert_eq!(diff.entities[0].removed, [a]);
+        assert_eq!(
+            diff.reused().collect::<Vec<_>>(),
+            [root, children[2], children[0]]
+        );
+        assert_eq!(diff.reordered().collect::<Vec<_>>(), [root]);
+        assert_eq!(diff.despawned, [children[1]]);
+        assert_eq!(diff.entities[2].inserted, [a]);
+
+        let spawned = diff.spawned().collect::<Vec<_>>();
+        assert_eq!(spawned.len(), 1);
+        assert_eq!(spawned[0].path, [Anchor::Named("fourth".into())]);
+    }
+
+    #[test]
+    fn skip_unchanged_components() {
+        let mut world = World::new();
+        world.skip_unchanged::<B>();
+        let root = world.spawn_empty().id();
+
+        template! { {(A, B(0))}; }.build(&mut world, root);
+        let a_ticks = world.entity(root).get_change_ticks::<A>().unwrap();
+        let b_ticks = world.entity(root).get_change_ticks::<B>().unwrap();
+        world.increment_change_tick();
+
+        // `A` doesn't opt in, so it is always written. `B` is unchanged.
+        template! { {(A, B(0))}; }.build(&mut world, root);
+        let entity = world.entity(root);
+        assert_ne!(
+            entity.get_change_ticks::<A>().unwrap().changed,
+            a_ticks.changed
+        );
+        assert_eq!(
+            entity.get_change_ticks::<B>().unwrap().changed,
+            b_ticks.changed
+        );
+        world.increment_change_tick();
+
+        template! { {(A, B(1))}; }.build(&mut world, root);
+        let entity = world.entity(root);
+        assert_eq!(entity.get::<B>(), Some(&B(1)));
+        assert_ne!(
+            entity.get_change_ticks::<B>().unwrap().changed,
+            b_ticks.changed
+        );
+    }
+
+    #[test]
+    fn skip_unchanged_compares_each_component_as_its_own_type() {
+        #[derive(Component, Clone, PartialEq, Debug)]
+        struct Label(String);
+
+        let mut world = World::new();
+        world.skip_unchanged::<B>().skip_unchanged::<Label>();
+        let root = world.spawn_empty().id();
+        let changed = |world: &World| {
+            let entity = world.entity(root);
+            (
+                entity.get_change_ticks::<B>().unwrap().changed,
+                entity.get_change_ticks::<Label>().unwrap().changed,
+            )
+        };
+
+        template! { {(B(0), Label("a".into()))}; }.build(&mut world, root);
+        let (b, label) = changed(&world);
+        world.increment_change_tick();
+
+        template! { {(B(0), Label("b".into()))}; }.build(&mut world, root);
+        assert_eq!(world.get::<Label>(root), Some(&Label("b".into())));
+        assert_eq!(changed(&world).0, b);
+        let label_changed = changed(&world).1;
+        assert_ne!(label_changed, label);
+        world.increment_change_tick();
+
+        template! { {(B(1), Label("b".into()))}; }.build(&mut world, root);
+        assert_eq!(world.get::<B>(root), Some(&B(1)));
+        assert_ne!(changed(&world).0, b);
+        assert_eq!(changed(&world).1, label_changed);
+    }
+
+    #[test]
+    fn keyed_lists() {
+        #[derive(Hash, PartialEq, Eq)]
+        struct Id(u32);
+
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let list = |ids: &[u8]| {
+            template! {
+                { A } [
+                    @for id in ids.iter().copied() => key(Id(id as u32)) { B(id) } [
+                        { A };
+                    ];
+                    { A };
+                ];
+            }
+        };
+
+        list(&[0, 1, 2]).build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(world.get::<B>(children[2]), Some(&B(2)));
+
+        list(&[2, 0]).build(&mut world, root);
+        assert_eq!(
+            world.get::<Children>(root).unwrap().to_vec(),
+            [children[2], children[0], children[3]]
+        );
+        assert!(world.get_entity(children[1]).is_err());
+    }
+
+    #[test]
+    fn lists_with_overlapping_keys_are_distinct() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let lists = |xs: &[u8], ys: &[u8]| {
+            template! {
+                { A } [
+                    @for x in xs.iter().copied() => key(x) { B(x) };
+                    @for y in ys.iter().copied() => key(y) { B(y) };
+                ];
+            }
+        };
+
+        lists(&[1, 2], &[2, 3]).build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(children.len(), 4);
+
+        lists(&[1], &[2, 3]).build(&mut world, root);
+        assert_eq!(
+            world.get::<Children>(root).unwrap().to_vec(),
+            [children[0], children[2], children[3]]
+        );
+        assert!(world.get_entity(children[1]).is_err());
+    }
+
+    #[test]
+    fn keys_of_different_types_are_distinct() {
+        assert_eq!(Key::new(1_u32), Key::new(1_u32));
+        assert_ne!(Key::new(1_u32), Key::new(2_u32));
+        assert_ne!(Key::new(1_u32), Key::new(1_u64));
+        assert_eq!(Key::new("a").downcast_ref::<&str>(), Some(&"a"));
+    }
+
+    #[test]
+    fn conditionals_keep_sibling_positions() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let conditional = |show: bool, value: Option<u8>| {
+            template! {
+                { A } [
+                    { B(0) };
+                    @if show {
+                        { B(1) };
+                    } @else {
+                        { A };
+                    };
+                    @if let Some(value) = value {
+                        { B(value) };
+                    };
+                    { B(2) };
+                ];
+            }
+        };
+
+        conditional(true, Some(3)).build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(children.len(), 4);
+        assert_eq!(world.get::<B>(children[2]), Some(&B(3)));
+
+        conditional(false, Some(4)).build(&mut world, root);
+        let new_children = world.get::<Children>(root).unwrap().to_vec();
+        assert!(world.get_entity(children[1]).is_err());
+        assert_eq!(world.get::<A>(new_children[1]), Some(&A));
+        assert_eq!(
+            [new_children[0], new_children[2], new_children[3]],
+            [children[0], children[2], children[3]]
+        );
+        assert_eq!(world.get::<B>(children[2]), Some(&B(4)));
+
+        conditional(false, None).build(&mut world, root);
+        assert!(world.get_entity(children[2]).is_err());
+        assert_eq!(
+            world.get::<Children>(root).unwrap().to_vec(),
+            [children[0], new_children[1], children[3]]
+        );
+    }
+
+    #[test]
+    fn conditionals_with_the_same_condition_are_distinct() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let show = true;
+        template! {
+            { A } [
+                @if show { { B(1) }; };
+                { A };
+                @if show { { B(2) }; };
+            ];
+        }
+        .build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(world.get::<B>(children[0]), Some(&B(1)));
+        assert_eq!(world.get::<B>(children[2]), Some(&B(2)));
+    }
+
+    #[test]
+    fn helpers_spliced_twice_number_their_scopes() {
+        fn row(show: bool, value: u8) -> Template {
+            template! {
+                { B(value) };
+                @if show { { A }; };
+                @for i in 0..2 => key(i) { B(i) };
+                @match show {
+                    true => { { A }; },
+                    false => {}
+                };
+            }
+        }
+
+        let mut world = World::new();
+        world.insert_resource(TemplateErrorMode::Panic);
+        let root = world.spawn_empty().id();
+
+        let rows = || {
+            template! {
+                { A } [
+                    @{ row(true, 10) };
+                    @{ row(true, 20) };
+                ];
+            }
+        };
+        rows().build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(children.len(), 10);
+        let anchors = world
+            .get::<Receipt>(root)
+            .unwrap()
+            .anchors()
+            .map(|(anchor, _)| anchor.to_string())
+            .collect::<HashSet<_>>();
+        assert!(anchors.contains("@if[0]:#0"));
+        assert!(anchors.contains("@if[0](1):#0"));
+        assert!(anchors.contains("@match[0](1):#0"));
+
+        // Rebuilding re-uses every entity.
+        rows().build(&mut world, root);
+        assert_eq!(world.get::<Children>(root).unwrap().to_vec(), children);
+    }
+
+    #[test]
+    fn match_arms_replace_entities() {
+        enum State {
+            Loading(u8),
+            Ready,
+        }
+
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let screen = |state: State| {
+            template! {
+                { A } [
+                    @match state {
+                        State::Loading(progress) if progress < 100 => {
+                            { B(progress) };
+                        },
+                        State::Loading(_) => {
+                            { A };
+                        },
+                        State::Ready => {
+                            { B(100) };
+                            { A };
+                        }
+                    };
+                ];
+            }
+        };
+
+        screen(State::Loading(10)).build(&mut world, root);
+        let loading = world.get::<Children>(root).unwrap().to_vec();
+
+        screen(State::Loading(20)).build(&mut world, root);
+        assert_eq!(world.get::<Children>(root).unwrap().to_vec(), loading);
+        assert_eq!(world.get::<B>(loading[0]), Some(&B(20)));
+
+        screen(State::Ready).build(&mut world, root);
+        let ready = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(ready.len(), 2);
+        assert!(world.get_entity(loading[0]).is_err());
+        assert_eq!(world.get::<B>(ready[0]), Some(&B(100)));
+    }
+
+    #[test]
+    fn matches_on_the_same_expression_are_distinct() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        let value = Some(1);
+        template! {
+            { A } [
+                @match value {
+                    Some(value) => {
+                        { B(value) };
+                    },
+                    None => {}
+                };
+                @match value {
+                    Some(value) => {
+                        { B(value + 1) };
+                    },
+                    None => {}
+                };
+            ];
+        }
+        .build(&mut world, root);
+        let children = world.get::<Children>(root).unwrap().to_vec();
+        assert_eq!(world.get::<B>(children[0]), Some(&B(1)));
+        assert_eq!(world.get::<B>(children[1]), Some(&B(2)));
+    }
+
+    mod unimported {
+        #[test]
+        fn template_macro_needs_no_imports() {
+            let template: crate::Template = crate::template! {
+                name: { super::A } [
+                    @for i in 0..3 => key(i) { super::B(i) };
+                ];
+                @if true {
+                    { super::A };
+                };
+            };
+            assert_eq!(template.len(), 2);
+        }
+    }
 }
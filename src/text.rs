@@ -0,0 +1,687 @@
+use std::sync::Arc;
+
+use bevy_ecs::reflect::ReflectComponent;
+use bevy_reflect::{
+    serde::{TypedReflectDeserializer, TypedReflectSerializer},
+    std_traits::ReflectDefault,
+    PartialReflect, Reflect, ReflectFromReflect, TypeRegistration, TypeRegistry,
+};
+use serde::de::DeserializeSeed;
+
+use crate::{Anchor, DynamicFragment, DynamicItem, DynamicSplice, DynamicTemplate};
+
+/// An error encountered while parsing a [`DynamicTemplate`] from text.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TemplateParseError {
+    /// The line the error was found on, starting from 1.
+    pub line: usize,
+    /// The column the error was found at, in characters starting from 1.
+    pub column: usize,
+    /// A description of the error.
+    pub message: String,
+}
+
+impl core::fmt::Display for TemplateParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl core::error::Error for TemplateParseError {}
+
+/// An error encountered while writing a [`DynamicTemplate`] as text, because a
+/// component value couldn't be serialized.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TemplateSerializeError {
+    /// The type path of the component.
+    pub type_path: String,
+    /// A description of the error.
+    pub message: String,
+}
+
+impl core::fmt::Display for TemplateSerializeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "cannot serialize `{}`: {}", self.type_path, self.message)
+    }
+}
+
+impl core::error::Error for TemplateSerializeError {}
+
+impl DynamicTemplate {
+    /// Parses a template from text, creating component values from the type
+    /// registrations in `registry`.
+    ///
+    /// The text format follows the grammar of the [`template`](crate::template)
+    /// macro, with component values written in [RON](https://docs.rs/ron)
+    /// rather than Rust:
+    ///
+    /// ```ignore
+    ///   <template> = *( <item> ";" )
+    ///       <item> = <name>? ( <splice> | <fragment> )
+    ///     <splice> = "@" <ident>                        -- a template in the `TemplateLibrary`.
+    ///   <fragment> = "{" ( <component> *( "," <component> ) ","? )? "}" <children>?
+    ///  <component> = <type path> ( "(" <ron> ")" | "=" <ron> )?
+    ///   <children> = "[" <template> "]"
+    ///       <name> = ( <ident> | <string> ) ":"
+    /// ```
+    ///
+    /// Types are found by their full type path, or by their short type path if
+    /// it isn't ambiguous. Every type must be registered with
+    /// `#[reflect(Component)]`. A component written without a value is created
+    /// from its reflected `Default`, and a value which leaves out fields takes
+    /// them from it. `//` starts a comment which runs to the end of the line.
+    ///
+    /// ```rust
+    /// # use i_cant_believe_its_not_bsn::*;
+    /// # use bevy::prelude::*;
+    /// let mut world = World::new();
+    /// world.init_resource::<AppTypeRegistry>();
+    /// {
+    ///     let mut registry = world.resource::<AppTypeRegistry>().write();
+    ///     registry.register::<Name>();
+    ///     registry.register::<Node>();
+    ///     registry.register::<Text>();
+    /// }
+    ///
+    /// let text = r#"
+    ///     // The title bar.
+    ///     title: { Name = "Title", Node(width: Percent(100.0)) } [
+    ///         { Text("Hello") };
+    ///     ];
+    /// "#;
+    /// let template =
+    ///     DynamicTemplate::parse(text, &world.resource::<AppTypeRegistry>().read()).unwrap();
+    ///
+    /// let root = world.spawn_empty().id();
+    /// template! { @{ template }; }.build(&mut world, root);
+    /// assert_eq!(world.get::<Name>(root).unwrap().as_str(), "Title");
+    /// ```
+    pub fn parse(text: &str, registry: &TypeRegistry) -> Result<Self, TemplateParseError> {
+        let mut parser = Parser {
+            text,
+            offset: 0,
+            registry,
+        };
+        let template = parser.template()?;
+        if parser.peek().is_some() {
+            return Err(parser.error(parser.offset, "expected a name, `{` or `@`"));
+        }
+        Ok(template)
+    }
+
+    /// Writes the template in the text format read by [`DynamicTemplate::parse`],
+    /// using the full type path of each component, and serializing component
+    /// values with the type registrations in `registry`. See
+    /// [`DynamicTemplate::extract`] for an example.
+    pub fn to_text(&self, registry: &TypeRegistry) -> Result<String, TemplateSerializeError> {
+        let mut text = String::new();
+        write_template(&mut text, self, registry, 0)?;
+        Ok(text)
+    }
+}
+
+/// Writes the items of a template, one per line, indented by `depth` levels.
+fn write_template(
+    text: &mut String,
+    template: &DynamicTemplate,
+    registry: &TypeRegistry,
+    depth: usize,
+) -> Result<(), TemplateSerializeError> {
+    let indent = "    ".repeat(depth);
+    for item in &template.items {
+        text.push_str(&indent);
+        let anchor = match item {
+            DynamicItem::Fragment(fragment) => &fragment.anchor,
+            DynamicItem::Splice(splice) => &splice.anchor,
+        };
+        if let Some(anchor) = anchor {
+            write_name(text, &anchor.to_string());
+            text.push_str(": ");
+        }
+        match item {
+            DynamicItem::Splice(splice) => {
+                text.push('@');
+                text.push_str(&splice.name);
+            }
+            DynamicItem::Fragment(fragment) => {
+                text.push('{');
+                for (index, component) in fragment.components.iter().enumerate() {
+                    text.push_str(if index == 0 { " " } else { ", " });
+                    write_component(text, component.as_ref(), registry)?;
+                }
+                if !fragment.components.is_empty() {
+                    text.push(' ');
+                }
+                text.push('}');
+                if !fragment.children.items.is_empty() {
+                    text.push_str(" [\n");
+                    write_template(text, &fragment.children, registry, depth + 1)?;
+                    text.push_str(&indent);
+                    text.push(']');
+                }
+            }
+        }
+        text.push_str(";\n");
+    }
+    Ok(())
+}
+
+/// Writes a name, quoting it unless it is an identifier.
+fn write_name(text: &mut String, name: &str) {
+    let mut chars = name.chars();
+    let is_ident = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    if is_ident {
+        text.push_str(name);
+        return;
+    }
+    text.push('"');
+    for c in name.chars() {
+        if matches!(c, '"' | '\\') {
+            text.push('\\');
+        }
+        text.push(c);
+    }
+    text.push('"');
+}
+
+/// Writes a component as its type path followed by its value in RON.
+fn write_component(
+    text: &mut String,
+    component: &dyn Reflect,
+    registry: &TypeRegistry,
+) -> Result<(), TemplateSerializeError> {
+    let type_path = component.reflect_type_path();
+    let serializer = TypedReflectSerializer::new(component.as_partial_reflect(), registry);
+    let value = ron::to_string(&serializer).map_err(|error| TemplateSerializeError {
+        type_path: type_path.to_string(),
+        message: error.to_string(),
+    })?;
+    text.push_str(type_path);
+    // Structs and tuples are written directly after the type, like in Rust.
+    if !value.starts_with('(') {
+        text.push_str(" = ");
+    }
+    text.push_str(&value);
+    Ok(())
+}
+
+/// A recursive descent parser for the text format of [`DynamicTemplate`].
+struct Parser<'a> {
+    text: &'a str,
+    offset: usize,
+    registry: &'a TypeRegistry,
+}
+
+impl<'a> Parser<'a> {
+    /// Returns an error at a byte offset into the text.
+    fn error(&self, offset: usize, message: impl Into<String>) -> TemplateParseError {
+        let before = &self.text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        TemplateParseError {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+
+    /// Skips whitespace and comments, then returns the next character.
+    fn peek(&mut self) -> Option<char> {
+        loop {
+            let rest = &self.text[self.offset..];
+            let trimmed = rest.trim_start();
+            self.offset += rest.len() - trimmed.len();
+            if !trimmed.starts_with("//") {
+                return trimmed.chars().next();
+            }
+            self.offset += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+    }
+
+    /// Consumes the next character if it is `expected`.
+    fn eat(&mut self, expected: char) -> bool {
+        let found = self.peek() == Some(expected);
+        if found {
+            self.offset += expected.len_utf8();
+        }
+        found
+    }
+
+    /// Consumes the next character, which must be `expected`.
+    fn expect(&mut self, expected: char, message: &str) -> Result<(), TemplateParseError> {
+        match self.eat(expected) {
+            true => Ok(()),
+            false => Err(self.error(self.offset, message)),
+        }
+    }
+
+    /// Consumes characters while `predicate` holds, returning them.
+    fn take_while(&mut self, mut predicate: impl FnMut(char) -> bool) -> &'a str {
+        let rest = &self.text[self.offset..];
+        let len = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.offset += len;
+        &rest[..len]
+    }
+
+    /// Parses an identifier, if there is one.
+    fn ident(&mut self) -> Option<&'a str> {
+        if !self.peek().is_some_and(|c| c.is_alphabetic() || c == '_') {
+            return None;
+        }
+        Some(self.take_while(|c| c.is_alphanumeric() || c == '_'))
+    }
+
+    fn template(&mut self) -> Result<DynamicTemplate, TemplateParseError> {
+        let mut items = Vec::new();
+        while !matches!(self.peek(), None | Some(']')) {
+            items.push(self.item()?);
+            self.expect(';', "expected `;` after an item")?;
+        }
+        Ok(DynamicTemplate { items })
+    }
+
+    /// Parses a quoted string, if there is one. Backslashes escape the next
+    /// character.
+    fn string(&mut self) -> Result<Option<String>, TemplateParseError> {
+        if !self.eat('"') {
+            return Ok(None);
+        }
+        let start = self.offset - 1;
+        let mut string = String::new();
+        let mut chars = self.text[self.offset..].chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => string.extend(chars.next()),
+                Some(c) => string.push(c),
+                None => return Err(self.error(start, "unterminated string")),
+            }
+        }
+        self.offset = self.text.len() - chars.as_str().len();
+        Ok(Some(string))
+    }
+
+    fn item(&mut self) -> Result<DynamicItem, TemplateParseError> {
+        let name = match self.ident() {
+            Some(name) => Some(name.to_string()),
+            None => self.string()?,
+        };
+        let anchor = match name {
+            Some(name) => {
+                self.expect(':', "expected `:` after a name")?;
+                Some(Anchor::Named(name))
+            }
+            None => None,
+        };
+        if self.eat('@') {
+            let Some(name) = self.ident() else {
+                return Err(self.error(self.offset, "expected the name of a template after `@`"));
+            };
+            return Ok(DynamicItem::Splice(DynamicSplice {
+                anchor,
+                name: name.to_string(),
+            }));
+        }
+        if !self.eat('{') {
+            return Err(self.error(self.offset, "expected a name, `{` or `@`"));
+        }
+
+        let mut components = Vec::new();
+        while !self.eat('}') {
+            components.push(self.component()?);
+            if !self.eat(',') {
+                self.expect('}', "expected `,` or `}` after a component")?;
+                break;
+            }
+        }
+        let mut children = DynamicTemplate::default();
+        if self.eat('[') {
+            children = self.template()?;
+            self.expect(']', "expected `]` after the children")?;
+        }
+        Ok(DynamicItem::Fragment(DynamicFragment {
+            anchor,
+            components,
+            children,
+        }))
+    }
+
+    fn component(&mut self) -> Result<Arc<dyn Reflect>, TemplateParseError> {
+        let start = self.offset;
+        let path = self.type_path()?;
+        let registration = self.registration(&path, start)?;
+        if registration.data::<ReflectComponent>().is_none() {
+            return Err(self.error(
+                start,
+                format!(
+                    "`{path}` isn't a reflected component, is it missing `#[reflect(Component)]`?"
+                ),
+            ));
+        }
+
+        let value = match self.peek() {
+            Some('(') => Some(self.value(registration, &path, true)?),
+            Some('=') => {
+                self.offset += 1;
+                self.peek();
+                Some(self.value(registration, &path, false)?)
+            }
+            _ => None,
+        };
+        match value {
+            Some(value) => from_reflect(registration, value.as_ref())
+                .ok_or_else(|| self.error(start, format!("cannot create `{path}` from its value"))),
+            None => registration
+                .data::<ReflectDefault>()
+                .map(|default| default.default())
+                .ok_or_else(|| {
+                    self.error(
+                        start,
+                        format!("`{path}` needs a value, since it doesn't reflect `Default`"),
+                    )
+                }),
+        }
+        .map(Arc::from)
+    }
+
+    /// Parses a type path, which may have generic arguments, normalizing the
+    /// whitespace in them to match the paths in the registry.
+    fn type_path(&mut self) -> Result<String, TemplateParseError> {
+        let start = self.offset;
+        if self.ident().is_none() {
+            return Err(self.error(self.offset, "expected a component type"));
+        }
+        let mut depth = 0usize;
+        loop {
+            let rest = &self.text[self.offset..];
+            if rest.starts_with("::") {
+                self.offset += 2;
+                if self.ident().is_none() {
+                    return Err(self.error(self.offset, "expected a type path segment"));
+                }
+            } else if rest.starts_with('<') {
+                self.offset += 1;
+                depth += 1;
+            } else if depth > 0 {
+                let Some(c) = rest.chars().next() else {
+                    return Err(self.error(self.offset, "expected `>`"));
+                };
+                self.offset += c.len_utf8();
+                if c == '>' {
+                    depth -= 1;
+                }
+            } else {
+                break;
+            }
+        }
+        let path = self.text[start..self.offset]
+            .split_whitespace()
+            .collect::<String>();
+        Ok(path.replace(',', ", "))
+    }
+
+    /// Finds the registration for a type path.
+    fn registration(
+        &self,
+        path: &str,
+        start: usize,
+    ) -> Result<&'a TypeRegistration, TemplateParseError> {
+        let registration = match path.contains("::") {
+            true => self.registry.get_with_type_path(path),
+            false => self.registry.get_with_short_type_path(path),
+        };
+        match registration {
+            Some(registration) => Ok(registration),
+            None if self.registry.is_ambiguous(path) => Err(self.error(
+                start,
+                format!("`{path}` is ambiguous, use its full type path instead"),
+            )),
+            None => Err(self.error(start, format!("`{path}` isn't a registered type"))),
+        }
+    }
+
+    /// Parses a RON value for a type. A parenthesized value runs to the
+    /// matching `)`, and any other value runs to the next `,`, `;` or closing
+    /// bracket which isn't nested inside it.
+    fn value(
+        &mut self,
+        registration: &TypeRegistration,
+        path: &str,
+        parenthesized: bool,
+    ) -> Result<Box<dyn PartialReflect>, TemplateParseError> {
+        let start = self.offset;
+        let mut depth = 0usize;
+        let mut chars = self.text[start..].char_indices();
+        let mut end = None;
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' | '\'' => loop {
+                    match chars.next() {
+                        Some((_, '\\')) => {
+                            chars.next();
+                        }
+                        Some((_, quote)) if quote == c => break,
+                        Some(_) => {}
+                        None => return Err(self.error(start + index, "unterminated string")),
+                    }
+                },
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' if depth == 0 => {
+                    end = Some(index);
+                    break;
+                }
+                ')' | ']' | '}' => {
+                    depth -= 1;
+                    if depth == 0 && parenthesized {
+                        end = Some(index + 1);
+                        break;
+                    }
+                }
+                ',' | ';' if depth == 0 => {
+                    end = Some(index);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        let end = start + end.unwrap_or(self.text.len() - start);
+        self.offset = end;
+
+        let ron = &self.text[start..end];
+        if ron.trim().is_empty() {
+            return Err(self.error(start, format!("expected a value for `{path}`")));
+        }
+        let invalid = |error: &dyn core::fmt::Display| {
+            self.error(start, format!("invalid value for `{path}`: {error}"))
+        };
+        let mut deserializer = ron::Deserializer::from_str(ron).map_err(|error| invalid(&error))?;
+        let value = TypedReflectDeserializer::new(registration, self.registry)
+            .deserialize(&mut deserializer)
+            .map_err(|error| invalid(&error))?;
+        deserializer.end().map_err(|error| invalid(&error))?;
+        Ok(value)
+    }
+}
+
+/// Creates a concrete value of a registered type from a possibly dynamic one,
+/// using the reflected `FromReflect`, or else the reflected `Default` with the
+/// value applied to it.
+fn from_reflect(
+    registration: &TypeRegistration,
+    value: &dyn PartialReflect,
+) -> Option<Box<dyn Reflect>> {
+    if let Some(value) = registration
+        .data::<ReflectFromReflect>()
+        .and_then(|from_reflect| from_reflect.from_reflect(value))
+    {
+        return Some(value);
+    }
+    let mut default = registration.data::<ReflectDefault>()?.default();
+    default.try_apply(value).ok()?;
+    Some(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Reflect, Default, Clone, PartialEq, Debug)]
+    #[reflect(Component, Default, PartialEq)]
+    struct Label(String);
+
+    #[derive(Component, Reflect, Default, Clone, PartialEq, Debug)]
+    #[reflect(Component, Default, PartialEq)]
+    struct Size {
+        width: f32,
+        height: f32,
+    }
+
+    #[derive(Component, Reflect, Clone, PartialEq, Debug)]
+    #[reflect(Component, PartialEq)]
+    enum Mode {
+        Off,
+        On(u32),
+    }
+
+    mod other {
+        use bevy::prelude::*;
+
+        #[derive(Component, Reflect, Default)]
+        #[reflect(Component, Default)]
+        pub struct Label;
+    }
+
+    fn registry() -> AppTypeRegistry {
+        let registry = AppTypeRegistry::default();
+        {
+            let mut registry = registry.write();
+            registry.register::<Label>();
+            registry.register::<Size>();
+            registry.register::<Mode>();
+        }
+        registry
+    }
+
+    fn parse(text: &str) -> Result<DynamicTemplate, TemplateParseError> {
+        DynamicTemplate::parse(text, &registry().read())
+    }
+
+    fn fragment(item: &DynamicItem) -> &DynamicFragment {
+        match item {
+            DynamicItem::Fragment(fragment) => fragment,
+            DynamicItem::Splice(_) => panic!("expected a fragment"),
+        }
+    }
+
+    fn component<T: Reflect>(fragment: &DynamicFragment, index: usize) -> &T {
+        fragment.components[index].downcast_ref().unwrap()
+    }
+
+    #[test]
+    fn parse_names_values_and_children() {
+        let template = parse(
+            r#"
+            // A comment.
+            header: { Label("title"), Size(width: 10.0), Mode = On(3) } [
+                { Label };
+                @footer;
+                named: @footer;
+            ];
+            { i_cant_believe_its_not_bsn::text::tests::Mode = Off, };
+            "#,
+        )
+        .unwrap();
+        assert_eq!(template.items.len(), 2);
+
+        let header = fragment(&template.items[0]);
+        assert_eq!(header.anchor, Some(Anchor::Named("header".into())));
+        assert_eq!(component::<Label>(header, 0), &Label("title".into()));
+        assert_eq!(
+            component::<Size>(header, 1),
+            &Size {
+                width: 10.0,
+                height: 0.0
+            }
+        );
+        assert_eq!(component::<Mode>(header, 2), &Mode::On(3));
+
+        let children = &header.children.items;
+        assert_eq!(
+            component::<Label>(fragment(&children[0]), 0),
+            &Label::default()
+        );
+        assert!(matches!(
+            &children[1],
+            DynamicItem::Splice(DynamicSplice { anchor: None, name }) if name == "footer"
+        ));
+        assert!(matches!(
+            &children[2],
+            DynamicItem::Splice(DynamicSplice {
+                anchor: Some(_),
+                ..
+            })
+        ));
+        assert_eq!(
+            component::<Mode>(fragment(&template.items[1]), 0),
+            &Mode::Off
+        );
+    }
+
+    #[test]
+    fn to_text_round_trips() {
+        let registry = registry();
+        let text = concat!(
+            "header: { i_cant_believe_its_not_bsn::text::tests::Label(\"a \\\"b\\\"\") } [\n",
+            "    \"two words\": { i_cant_believe_its_not_bsn::text::tests::Mode = On(3) };\n",
+            "    @footer;\n",
+            "    {};\n",
+            "];\n",
+        );
+        let template = DynamicTemplate::parse(text, &registry.read()).unwrap();
+        assert_eq!(fragment(&template.items[0]).children.items.len(), 3);
+        assert_eq!(template.to_text(&registry.read()).unwrap(), text);
+    }
+
+    #[test]
+    fn parse_errors_report_their_position() {
+        let error = |text: &str| {
+            let error = parse(text).unwrap_err();
+            (error.line, error.column, error.message)
+        };
+        assert_eq!(
+            error("{ Label(\"a\") }"),
+            (1, 15, "expected `;` after an item".into())
+        );
+        assert_eq!(
+            error("{ Label }[\n    { Missing };\n];"),
+            (2, 7, "`Missing` isn't a registered type".into())
+        );
+        assert_eq!(
+            error("{ Mode }; "),
+            (
+                1,
+                3,
+                "`Mode` needs a value, since it doesn't reflect `Default`".into()
+            )
+        );
+        assert!(error("{ Size(depth: 1.0) };")
+            .2
+            .starts_with("invalid value for `Size`"));
+
+        // Short type paths which are shared by more than one type need to be
+        // written out in full.
+        let registry = registry();
+        registry.write().register::<other::Label>();
+        let error = DynamicTemplate::parse("{ Label };", &registry.read()).unwrap_err();
+        assert_eq!(
+            error.message,
+            "`Label` is ambiguous, use its full type path instead"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use core::any::TypeId;
+
+use bevy_ecs::prelude::*;
+
+use crate::{
+    state::{build_widget, render},
+    Anchor, Key, Prototype, Template, TemplateDiff,
+};
+
+/// A reusable piece of UI, or any other hierarchy, which is rendered to a
+/// template from a set of props. Implement this on the props struct, and add
+/// widgets to templates with `@widget` in the [`template`](crate::template)
+/// macro or as a [`WidgetPrototype`].
+///
+/// Unlike a function returning a template, a widget is a single prototype, so
+/// it has its own identity among its siblings: its kind is the type of the
+/// widget. When a widget is built again with different props, its template is
+/// rebuilt on the same entities.
+///
+/// ```rust
+/// # use i_cant_believe_its_not_bsn::*;
+/// # use bevy::prelude::*;
+/// #[derive(Clone)]
+/// struct LabeledButton {
+///     label: String,
+/// }
+///
+/// impl Widget for LabeledButton {
+///     fn render(&self) -> Template {
+///         template! {
+///             { Button } [
+///                 { Text::new(self.label.clone()) };
+///             ];
+///         }
+///     }
+/// }
+///
+/// template! {
+///     { Node::default() } [
+///         ok: @widget { LabeledButton { label: "Ok".to_string() } };
+///         cancel: @widget { LabeledButton { label: "Cancel".to_string() } };
+///     ];
+/// };
+/// ```
+pub trait Widget: Clone + Send + Sync + 'static {
+    /// Renders the widget to a template. The template is built on the widget's
+    /// entity in the same way as [`BuildTemplate::build`]: the first prototype
+    /// is built on the entity itself, and any others on their own entities.
+    ///
+    /// While rendering, a widget can keep local state on its entity with
+    /// [`state`](crate::state).
+    fn render(&self) -> Template;
+}
+
+/// A widget in a template, along with its anchor. It implements [`Prototype`]
+/// and can be stored or used as a `Box<dyn Prototype>`.
+#[derive(Clone)]
+pub struct WidgetPrototype<W: Widget> {
+    /// The anchor of the widget, used to identify it across builds.
+    pub anchor: Option<Anchor>,
+    /// The props of the widget.
+    pub widget: W,
+}
+
+impl<W: Widget> Prototype for WidgetPrototype<W> {
+    fn name(&self) -> Option<String> {
+        self.anchor.as_ref().map(ToString::to_string)
+    }
+
+    fn anchor(&self) -> Option<Anchor> {
+        self.anchor.clone()
+    }
+
+    fn kind(&self) -> Key {
+        Key::new(TypeId::of::<W>())
+    }
+
+    fn build(self: Box<Self>, world: &mut World, entity: Entity) {
+        build_widget(world, entity, self.widget);
+    }
+
+    fn diff(&self, world: &World, entity: Option<Entity>, diff: &mut TemplateDiff) {
+        diff.roots(world, entity, &render(world, entity, &self.widget).0);
+    }
+}
+
+impl<W: Widget> IntoIterator for WidgetPrototype<W> {
+    type Item = Box<dyn Prototype + Send + Sync>;
+    type IntoIter = core::iter::Once<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        std::iter::once(Box::new(self) as Box<_>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use crate::*;
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Label(String);
+
+    #[derive(Component, Clone, PartialEq, Debug)]
+    struct Icon;
+
+    #[derive(Clone)]
+    struct Button {
+        label: String,
+        icon: bool,
+    }
+
+    impl Widget for Button {
+        fn render(&self) -> Template {
+            template! {
+                { Label(self.label.clone()) } [
+                    @if self.icon {
+                        { Icon };
+                    };
+                ];
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Spacer;
+
+    impl Widget for Spacer {
+        fn render(&self) -> Template {
+            template! { { Label("spacer".to_string()) }; }
+        }
+    }
+
+    fn toolbar(label: &str, icon: bool) -> Template {
+        template! {
+            { Node::default() } [
+                @widget { Button { label: label.to_string(), icon } };
+            ];
+        }
+    }
+
+    #[test]
+    fn widgets_rebuild_in_place() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        toolbar("save", false).build(&mut world, root);
+        let button = world.get::<Children>(root).unwrap()[0];
+        assert_eq!(world.get::<Label>(button), Some(&Label("save".to_string())));
+        assert!(world.get::<Children>(button).is_none());
+
+        toolbar("load", true).build(&mut world, root);
+        assert_eq!(world.get::<Children>(root).unwrap()[0], button);
+        assert_eq!(world.get::<Label>(button), Some(&Label("load".to_string())));
+        let icon = world.get::<Children>(button).unwrap()[0];
+        assert_eq!(world.get::<Icon>(icon), Some(&Icon));
+
+        let diff = TemplateDiff::new(&world, root, &toolbar("load", true)).unwrap();
+        assert_eq!(diff.spawned().count(), 0);
+        assert_eq!(diff.reused().collect::<Vec<_>>(), [root, button, icon]);
+    }
+
+    #[test]
+    fn widgets_of_different_types_are_distinct() {
+        let mut world = World::new();
+        let root = world.spawn_empty().id();
+
+        toolbar("save", false).build(&mut world, root);
+        let button = world.get::<Children>(root).unwrap()[0];
+
+        template! {
+            { Node::default() } [
+                @widget { Spacer };
+            ];
+        }
+        .build(&mut world, root);
+        let spacer = world.get::<Children>(root).unwrap()[0];
+        assert_ne!(spacer, button);
+        assert!(world.get_entity(button).is_err());
+    }
+}
@@ -0,0 +1,8 @@
+//! Checks that mistakes in `template!` are reported at the tokens which caused
+//! them. Run with `TRYBUILD=overwrite` to update the expected errors.
+
+#[test]
+fn template_errors() {
+    let tests = trybuild::TestCases::new();
+    tests.compile_fail("tests/ui/*.rs");
+}
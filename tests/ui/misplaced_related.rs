@@ -0,0 +1,11 @@
+use i_cant_believe_its_not_bsn::*;
+
+fn main() {
+    let _ = template! {
+        { Name::new("a") } [
+            Following => { Name::new("b") };
+        ] [
+            { Name::new("c") };
+        ];
+    };
+}
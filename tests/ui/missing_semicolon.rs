@@ -0,0 +1,8 @@
+use i_cant_believe_its_not_bsn::*;
+
+fn main() {
+    let _ = template! {
+        { Name::new("a") }
+        { Name::new("b") };
+    };
+}
@@ -0,0 +1,10 @@
+use i_cant_believe_its_not_bsn::*;
+
+#[derive(Clone)]
+struct NotABundle;
+
+fn main() {
+    let _ = template! {
+        { NotABundle };
+    };
+}
@@ -0,0 +1,9 @@
+use i_cant_believe_its_not_bsn::*;
+
+fn main() {
+    let _ = template! {
+        @while true {
+            { Name::new("a") };
+        };
+    };
+}